@@ -494,8 +494,8 @@ fn main() {
         .map_err(|_| Err(()))
         .for_each({
             |event: Event| match event {
-                Event::Disconnected(addr) => {
-                    log_line!("*** Peer with address {} disconnected", addr);
+                Event::PeerDisconnected(peer) => {
+                    log_line!("*** Peer ({}) disconnected", peer);
                     log_line!("*** Use Ctrl+C to exit");
                     future::err(Ok(()))
                 },
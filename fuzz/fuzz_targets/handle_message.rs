@@ -0,0 +1,11 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate saltyrtc_client;
+
+use saltyrtc_client::Role;
+
+fuzz_target!(|data: &[u8]| {
+    // Handle a message as both initiator and responder. Should never panic.
+    saltyrtc_client::fuzz_handle_message(Role::Initiator, data);
+    saltyrtc_client::fuzz_handle_message(Role::Responder, data);
+});
@@ -4,6 +4,8 @@
 //!
 //! A sealed box consists of the encrypted message bytes and a nonce.
 
+use std::io::{self, Write};
+
 use rmp_serde as rmps;
 use rmpv::Value;
 use rust_sodium::crypto::box_::NONCEBYTES;
@@ -11,7 +13,7 @@ use rust_sodium::crypto::box_::NONCEBYTES;
 use errors::{SignalingError, SignalingResult};
 use crypto::{KeyPair, PublicKey, AuthToken};
 use protocol::Nonce;
-use protocol::messages::Message;
+use protocol::messages::{Message, UnknownFields};
 
 /// An open box (unencrypted message + nonce).
 #[derive(Debug, PartialEq)]
@@ -66,47 +68,90 @@ impl OpenBox<Message> {
     ///
     /// This should only be necessary for the server-hello message. All other
     /// messages are encrypted.
-    pub(crate) fn decode(bbox: ByteBox) -> SignalingResult<Self> {
-        let message = Message::from_msgpack(&bbox.bytes)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
-        Ok(Self::new(message, bbox.nonce))
+    pub(crate) fn decode(bbox: ByteBox, unknown_fields: UnknownFields) -> SignalingResult<Self> {
+        Self::decode_bytes(&bbox.bytes, bbox.nonce, unknown_fields)
+    }
+
+    /// Like [`decode`](#method.decode), but only borrow the message bytes,
+    /// so that the caller can still inspect them if decoding fails.
+    pub(crate) fn decode_bytes(bytes: &[u8], nonce: Nonce, unknown_fields: UnknownFields) -> SignalingResult<Self> {
+        let message = decode_message(bytes, unknown_fields)?;
+        Ok(Self::new(message, nonce))
     }
 
     /// Decrypt an encrypted message into an [`OpenBox`](struct.OpenBox.html).
-    pub(crate) fn decrypt(bbox: ByteBox, keypair: &KeyPair, other_key: &PublicKey) -> SignalingResult<Self> {
+    pub(crate) fn decrypt(
+        bbox: ByteBox,
+        keypair: &KeyPair,
+        other_key: &PublicKey,
+        unknown_fields: UnknownFields,
+    ) -> SignalingResult<Self> {
+        Self::decrypt_bytes(&bbox.bytes, bbox.nonce, keypair, other_key, unknown_fields)
+    }
+
+    /// Like [`decrypt`](#method.decrypt), but only borrow the message bytes,
+    /// so that the caller can still inspect them if decryption fails.
+    pub(crate) fn decrypt_bytes(
+        bytes: &[u8],
+        nonce: Nonce,
+        keypair: &KeyPair,
+        other_key: &PublicKey,
+        unknown_fields: UnknownFields,
+    ) -> SignalingResult<Self> {
         let decrypted: Vec<u8> = keypair.decrypt(
             // The message bytes to be decrypted
-            &bbox.bytes,
+            bytes,
             // The nonce. The unsafe call to `clone()` is required because the
             // nonce needs to be used both for decrypting, as well as being
             // passed along with the message bytes.
-            unsafe { bbox.nonce.clone() },
+            unsafe { nonce.clone() },
             // The public key of the recipient
             other_key
-        ).map_err(|e| SignalingError::Decode(format!("Cannot decrypt message payload: {}", e)))?;
+        ).map_err(|e| {
+            debug!("Cannot decrypt message payload: {}", e);
+            SignalingError::DecryptionFailed { from: nonce.source_identity() }
+        })?;
 
         log_decrypted_bytes(&decrypted);
 
-        let message = Message::from_msgpack(&decrypted)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+        let message = decode_message(&decrypted, unknown_fields)?;
 
-        Ok(Self::new(message, bbox.nonce))
+        Ok(Self::new(message, nonce))
     }
 
     /// Decrypt token message using the `auth_token` using secret key cryptography.
-    pub(crate) fn decrypt_token(bbox: ByteBox, auth_token: &AuthToken) -> SignalingResult<Self> {
+    pub(crate) fn decrypt_token(
+        bbox: ByteBox,
+        auth_token: &AuthToken,
+        unknown_fields: UnknownFields,
+    ) -> SignalingResult<Self> {
         let decrypted = auth_token.decrypt(&bbox.bytes, unsafe { bbox.nonce.clone() })
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+            .map_err(|e| {
+                debug!("Cannot decrypt token message payload: {}", e);
+                SignalingError::DecryptionFailed { from: bbox.nonce.source_identity() }
+            })?;
 
         log_decrypted_bytes(&decrypted);
 
-        let message = Message::from_msgpack(&decrypted)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+        let message = decode_message(&decrypted, unknown_fields)?;
 
         Ok(Self::new(message, bbox.nonce))
     }
 }
 
+/// Decode msgpack bytes into a [`Message`](../protocol/messages/enum.Message.html),
+/// rejecting unknown fields if requested.
+fn decode_message(bytes: &[u8], unknown_fields: UnknownFields) -> SignalingResult<Message> {
+    let message = Message::from_msgpack(bytes)
+        .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+    if unknown_fields == UnknownFields::Reject {
+        Message::reject_unknown_fields(bytes, message.get_type())?;
+    }
+    #[cfg(feature = "debug-tools")]
+    trace!("Decoded message: {}", message.to_json());
+    Ok(message)
+}
+
 impl OpenBox<Value> {
     pub(crate) fn new(message: Value, nonce: Nonce) -> Self {
         OpenBox { message, nonce }
@@ -140,7 +185,10 @@ impl OpenBox<Value> {
             unsafe { bbox.nonce.clone() },
             // The public key of the recipient
             other_key
-        ).map_err(|e| SignalingError::Decode(format!("Cannot decrypt message payload: {}", e)))?;
+        ).map_err(|e| {
+            debug!("Cannot decrypt message payload: {}", e);
+            SignalingError::DecryptionFailed { from: bbox.nonce.source_identity() }
+        })?;
 
         log_decrypted_bytes(&decrypted);
 
@@ -165,6 +213,12 @@ impl ByteBox {
         ByteBox { bytes, nonce }
     }
 
+    /// Split a received frame into nonce and message bytes.
+    ///
+    /// Frames without any message bytes are rejected. Every SaltyRTC message
+    /// has a payload, and even an empty encrypted payload would still contain
+    /// the MAC. Payloads that are too short to contain a MAC are rejected
+    /// when decrypting.
     pub(crate) fn from_slice(bytes: &[u8]) -> SignalingResult<Self> {
         if bytes.len() <= NONCEBYTES {
             return Err(SignalingError::Decode("Message is too short".into()));
@@ -177,10 +231,19 @@ impl ByteBox {
 
     pub(crate) fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(NONCEBYTES + self.bytes.len());
-        bytes.extend(self.nonce.into_bytes().iter());
-        bytes.extend(self.bytes.iter());
+        self.write_to(&mut bytes).expect("Writing byte box to vector failed");
         bytes
     }
+
+    /// Write the nonce followed by the message bytes to `w`.
+    ///
+    /// Unlike [`into_bytes`](#method.into_bytes), this does not allocate an
+    /// intermediate buffer.
+    pub(crate) fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(self.nonce.cookie().as_bytes())?;
+        w.write_all(&self.nonce.message_id())?;
+        w.write_all(&self.bytes)
+    }
 }
 
 #[cfg(feature = "msgpack-debugging")]
@@ -205,7 +268,8 @@ fn log_decrypted_bytes(decrypted: &[u8]) {
 mod tests {
     use protocol::cookie::Cookie;
     use protocol::csn::CombinedSequenceSnapshot;
-    use protocol::types::Address;
+    use protocol::types::{Address, Identity};
+    use rust_sodium::crypto::box_::MACBYTES;
 
     use super::*;
 
@@ -267,11 +331,54 @@ mod tests {
         assert_eq!(format!("{}", err2), "Decoding error: Message is too short");
     }
 
+    #[test]
+    fn byte_box_write_to() {
+        let bbox = ByteBox::new(vec![9, 10, 11], create_test_nonce());
+        let mut written: Vec<u8> = vec![];
+        bbox.write_to(&mut written).unwrap();
+        assert_eq!(written.len(), NONCEBYTES + 3);
+        assert_eq!(written, bbox.into_bytes());
+    }
+
+    /// A frame containing only a nonce is rejected.
+    #[test]
+    fn byte_box_from_slice_nonce_only() {
+        let frame = create_test_nonce().into_bytes();
+        assert_eq!(frame.len(), NONCEBYTES);
+        assert_eq!(
+            ByteBox::from_slice(&frame),
+            Err(SignalingError::Decode("Message is too short".into()))
+        );
+    }
+
+    /// A payload that is too short to contain a MAC fails to decrypt with
+    /// a clear error.
+    #[test]
+    fn byte_box_decrypt_too_short_for_mac() {
+        let mut frame = create_test_nonce().into_bytes().to_vec();
+        frame.extend(&[0u8; MACBYTES - 1]);
+        let keypair_tx = KeyPair::new();
+        let keypair_rx = KeyPair::new();
+
+        let bbox = ByteBox::from_slice(&frame).unwrap();
+        assert_eq!(bbox.bytes.len(), MACBYTES - 1);
+        assert_eq!(
+            OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), UnknownFields::Ignore),
+            Err(SignalingError::DecryptionFailed { from: Identity::Responder(17) })
+        );
+
+        let bbox = ByteBox::from_slice(&frame).unwrap();
+        assert_eq!(
+            OpenBox::<Message>::decrypt_token(bbox, &AuthToken::new(), UnknownFields::Ignore),
+            Err(SignalingError::DecryptionFailed { from: Identity::Responder(17) })
+        );
+    }
+
     #[test]
     fn byte_box_decode_message() {
         let nonce = create_test_nonce();
         let bbox = ByteBox::new(create_test_msg_bytes(), nonce);
-        let obox = OpenBox::<Message>::decode(bbox).unwrap();
+        let obox = OpenBox::<Message>::decode(bbox, UnknownFields::Ignore).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
     }
 
@@ -283,7 +390,7 @@ mod tests {
         let keypair_rx = KeyPair::new();
         let encrypted = keypair_tx.encrypt(&bytes, unsafe { nonce.clone() }, keypair_rx.public_key());
         let bbox = ByteBox::new(encrypted, nonce);
-        let obox = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key()).unwrap();
+        let obox = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), UnknownFields::Ignore).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
     }
 
@@ -303,7 +410,7 @@ mod tests {
         let bbox = ByteBox::new(encrypted, nonce);
 
         // Decrypt byte box
-        let obox = OpenBox::decrypt_token(bbox, &auth_token).unwrap();
+        let obox = OpenBox::decrypt_token(bbox, &auth_token, UnknownFields::Ignore).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
     }
 
@@ -321,7 +428,7 @@ mod tests {
 
         // First, make sure that decrypting this as message fails.
         let bbox = ByteBox::new(encrypted.clone(), unsafe { nonce.clone() });
-        let decrypt_as_message = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key());
+        let decrypt_as_message = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), UnknownFields::Ignore);
         assert!(decrypt_as_message.is_err());
 
         // Then decrypt as value.
@@ -9,6 +9,7 @@ use std::io::Write;
 
 use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
 use rust_sodium::crypto::{box_, secretbox};
+use rust_sodium::utils::memcmp;
 use rust_sodium_sys::crypto_scalarmult_base;
 use serde::ser::{Serialize, Serializer};
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
@@ -52,9 +53,20 @@ pub fn private_key_from_hex_str(hex_str: &str) -> SaltyResult<PrivateKey> {
         .ok_or_else(|| SaltyError::Decode("Invalid private key hex string".to_string()))
 }
 
+/// Compare two byte slices in constant time.
+///
+/// Returns `false` if the slices differ in length. The length itself is not
+/// treated as secret.
+pub fn verify_equal(a: &[u8], b: &[u8]) -> bool {
+    libsodium_init_or_panic();
+    a.len() == b.len() && memcmp(a, b)
+}
+
 
 /// Wrapper for holding a public/private key pair and encrypting/decrypting messages.
-#[derive(Debug, PartialEq, Eq)]
+///
+/// The private key is overwritten with zeroes when the key pair is dropped.
+#[derive(PartialEq, Eq)]
 pub struct KeyPair {
     public_key: PublicKey,
     private_key: PrivateKey,
@@ -142,6 +154,9 @@ impl KeyPair {
     /// [`SignalingError::Crypto`](../enum.SignalingError.html#variant.Crypto)
     /// is returned.
     pub(crate) fn decrypt(&self, data: &[u8], nonce: Nonce, other_key: &PublicKey) -> SignalingResult<Vec<u8>> {
+        if data.len() < box_::MACBYTES {
+            return Err(SignalingError::Crypto("Ciphertext is too short to contain a MAC".to_string()));
+        }
         let rust_sodium_nonce: box_::Nonce = nonce.into();
         box_::open(data, &rust_sodium_nonce, other_key, &self.private_key)
             .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))
@@ -149,11 +164,27 @@ impl KeyPair {
 
 }
 
+/// Implementation required to avoid leaking the private key into logs.
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "KeyPair {{ public_key: {:?}, private_key: <redacted> }}", self.public_key)
+    }
+}
+
 
 /// Wrapper for holding an auth token and encrypting / decrypting messages.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// The secret key is overwritten with zeroes when the auth token is dropped.
+#[derive(Clone, PartialEq, Eq)]
 pub struct AuthToken(SecretKey);
 
+/// Implementation required to avoid leaking the secret key into logs.
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "AuthToken(<redacted>)")
+    }
+}
+
 impl AuthToken {
 
     /// Create a new auth token.
@@ -216,6 +247,9 @@ impl AuthToken {
     /// [`SignalingError::Crypto`](../enum.SignalingError.html#variant.Crypto)
     /// is returned.
     pub(crate) fn decrypt(&self, ciphertext: &[u8], nonce: Nonce) -> SignalingResult<Vec<u8>> {
+        if ciphertext.len() < secretbox::MACBYTES {
+            return Err(SignalingError::Crypto("Ciphertext is too short to contain a MAC".to_string()));
+        }
         let rust_sodium_nonce: secretbox::Nonce = nonce.into();
         secretbox::open(ciphertext, &rust_sodium_nonce, self.secret_key())
             .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))
@@ -390,6 +424,15 @@ impl TestRandom for PublicKey {
 mod tests {
     use super::*;
 
+    #[test]
+    fn verify_equal_slices() {
+        assert!(verify_equal(&[], &[]));
+        assert!(verify_equal(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!verify_equal(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!verify_equal(&[1, 2, 3], &[1, 2]));
+        assert!(!verify_equal(&[], &[0]));
+    }
+
     #[test]
     fn new() {
         for _ in 0..255 {
@@ -422,6 +465,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn public_key_hex() {
+        for _ in 0..255 {
+            let ks = KeyPair::new();
+            let hex = ks.public_key_hex();
+            assert_eq!(hex.len(), 64);
+            assert!(hex.chars().all(|c| c.is_digit(16) && !c.is_uppercase()));
+            assert_eq!(HEXLOWER.decode(hex.as_bytes()).unwrap(), ks.public_key().as_ref());
+            assert_eq!(&public_key_from_hex_str(&hex).unwrap(), ks.public_key());
+        }
+    }
+
     /// Test the `KeyPair::from_private_key` method against a precomputed
     /// public/private key pair.
     #[test]
@@ -555,6 +610,48 @@ mod tests {
         assert_eq!((deref2.0).0, zero_bytes);
     }
 
+    /// The debug representation of a KeyPair must not contain the private key.
+    #[test]
+    fn keypair_debug_redacted() {
+        let keypair = KeyPair::new();
+        let debug = format!("{:?}", keypair);
+        assert_eq!(
+            debug,
+            format!("KeyPair {{ public_key: {:?}, private_key: <redacted> }}", keypair.public_key())
+        );
+        assert!(!debug.contains(&format!("{:?}", &keypair.private_key().0[..])));
+        assert!(!debug.contains(&keypair.private_key_hex()));
+    }
+
+    /// The debug representation of an AuthToken must not contain the secret key.
+    #[test]
+    fn auth_token_debug_redacted() {
+        let token = AuthToken::new();
+        let debug = format!("{:?}", token);
+        assert_eq!(debug, "AuthToken(<redacted>)");
+        assert!(!debug.contains(&format!("{:?}", token.secret_key_bytes())));
+        assert!(!debug.contains(&HEXLOWER.encode(token.secret_key_bytes())));
+    }
+
+    /// Make sure that the private key of a KeyPair is zeroed on drop.
+    ///
+    /// In contrast to the test above, the memory is never deallocated, so
+    /// it can safely be inspected after running the destructor.
+    #[test]
+    fn keypair_zero_on_drop() {
+        use std::mem::ManuallyDrop;
+        use std::ptr;
+
+        let mut keypair = ManuallyDrop::new(KeyPair::new());
+        let key_ptr = &keypair.private_key.0 as *const [u8; 32];
+        assert_ne!(unsafe { *key_ptr }, [0; 32]);
+
+        // Run the destructor
+        unsafe { ptr::drop_in_place(&mut *keypair as *mut KeyPair) };
+
+        assert_eq!(unsafe { *key_ptr }, [0; 32]);
+    }
+
     #[test]
     fn unsigned_keys_sign_decrypt() {
         // Create keypairs
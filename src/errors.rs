@@ -2,12 +2,23 @@
 //!
 //! The implementation is done using the
 //! [`failure`](https://crates.io/crates/failure) crate.
+//!
+//! There are two error hierarchies with a clear boundary between them:
+//!
+//! - [`SignalingError`](enum.SignalingError.html) is internal. It is returned
+//!   by everything below the [`SaltyClient`](../struct.SaltyClient.html),
+//!   including the box decoding and decryption in the `boxes` module.
+//! - [`SaltyError`](enum.SaltyError.html) is exposed to the user of the
+//!   library. Signaling errors are converted through the `From` impl when
+//!   they cross the public API.
 
 use std::convert::From;
 
 use rmp_serde::decode::Error as SerdeDecodeError;
 use tokio_timer::TimeoutError;
 
+use protocol::types::Identity;
+
 
 /// Re-exported [`Error`](../../failure/struct.Error.html) type from the
 /// [failure crate](https://crates.io/crates/failure).
@@ -58,9 +69,11 @@ impl From<SignalingError> for SaltyError {
             SignalingError::Crypto(msg) => SaltyError::Crypto(msg),
             SignalingError::CsnOverflow => SaltyError::Crypto(e.to_string()),
             SignalingError::Decode(msg) => SaltyError::Decode(msg),
+            SignalingError::DecryptionFailed { .. } => SaltyError::Crypto(e.to_string()),
             SignalingError::InitiatorCouldNotDecrypt => SaltyError::Crypto(e.to_string()),
             SignalingError::InvalidMessage(_) => SaltyError::Protocol(e.to_string()),
             SignalingError::InvalidNonce(_) => SaltyError::Protocol(e.to_string()),
+            SignalingError::PeerCookieChanged(_) => SaltyError::Protocol(e.to_string()),
             SignalingError::InvalidStateTransition(_) => SaltyError::Crash(e.to_string()),
             SignalingError::NoSharedTask => SaltyError::NoSharedTask,
             SignalingError::Protocol(msg) => SaltyError::Protocol(msg),
@@ -92,10 +105,27 @@ pub(crate) enum SignalingError {
     #[fail(display = "Invalid nonce: {}", _0)]
     InvalidNonce(String),
 
+    /// The cookie of a peer has changed.
+    ///
+    /// This may indicate a spoofing attempt or a restart of the peer.
+    #[fail(display = "Invalid nonce: Cookie from {} has changed", _0)]
+    PeerCookieChanged(Identity),
+
     /// A problem with Libsodium or with encrypting or decrypting data.
     #[fail(display = "Crypto error: {}", _0)]
     Crypto(String),
 
+    /// A message could not be decrypted (authentication failed).
+    ///
+    /// This indicates either a wrong key or a tampered message, as opposed
+    /// to a [`Decode`](enum.SignalingError.html#variant.Decode) error, which
+    /// is returned if a decrypted message cannot be parsed.
+    #[fail(display = "Could not decrypt message from {}", from)]
+    DecryptionFailed {
+        /// The sender of the message.
+        from: Identity,
+    },
+
     /// A CSN overflowed.
     /// This is extremely unlikely and must always be treated as a protocol error.
     #[fail(display = "CSN overflow")]
@@ -141,6 +171,41 @@ pub(crate) enum SignalingError {
     Crash(String),
 }
 
+impl SignalingError {
+    /// Return whether this error is fatal.
+    ///
+    /// A fatal error indicates a protocol violation, a crypto failure or a
+    /// bug. Retrying the same session is pointless, the connection must be
+    /// closed.
+    ///
+    /// A non-fatal error only affects a single message or a single peer and
+    /// may be recovered from, e.g. by reconnecting:
+    ///
+    /// - `Decode`: A single message could not be decoded.
+    /// - `DecryptionFailed`: A single message could not be decrypted.
+    /// - `SendError`: The server could not relay a message.
+    /// - `InitiatorCouldNotDecrypt`: A responder sent a message the initiator
+    ///   could not decrypt. The responder will be dropped.
+    pub(crate) fn is_fatal(&self) -> bool {
+        match *self {
+            SignalingError::Decode(_) => false,
+            SignalingError::InvalidNonce(_) => true,
+            SignalingError::PeerCookieChanged(_) => true,
+            SignalingError::Crypto(_) => true,
+            SignalingError::DecryptionFailed { .. } => false,
+            SignalingError::CsnOverflow => true,
+            SignalingError::InvalidStateTransition(_) => true,
+            SignalingError::InvalidMessage(_) => true,
+            SignalingError::Protocol(_) => true,
+            SignalingError::SendError => false,
+            SignalingError::NoSharedTask => true,
+            SignalingError::TaskInitialization(_) => true,
+            SignalingError::InitiatorCouldNotDecrypt => false,
+            SignalingError::Crash(_) => true,
+        }
+    }
+}
+
 /// A result with [`SignalingError`](enum.SignalingError.html) as error type.
 pub(crate) type SignalingResult<T> = ::std::result::Result<T, SignalingError>;
 
@@ -156,4 +221,31 @@ pub enum BuilderError {
     /// No task has been added.
     #[fail(display = "No task specified")]
     MissingTask,
+
+    /// A key or token could not be decoded.
+    #[fail(display = "Decoding error: {}", _0)]
+    Decode(String),
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signaling_error_is_fatal() {
+        assert!(SignalingError::InvalidStateTransition("foo".into()).is_fatal());
+        assert!(SignalingError::InvalidNonce("foo".into()).is_fatal());
+        assert!(SignalingError::PeerCookieChanged(Identity::Initiator).is_fatal());
+        assert!(SignalingError::Crash("foo".into()).is_fatal());
+        assert!(SignalingError::CsnOverflow.is_fatal());
+    }
+
+    #[test]
+    fn signaling_error_is_not_fatal() {
+        assert!(!SignalingError::Decode("foo".into()).is_fatal());
+        assert!(!SignalingError::SendError.is_fatal());
+        assert!(!SignalingError::InitiatorCouldNotDecrypt.is_fatal());
+        assert!(!SignalingError::DecryptionFailed { from: Identity::Server }.is_fatal());
+    }
 }
@@ -0,0 +1,86 @@
+//! Entry points for fuzzing the message handling.
+//!
+//! This module is only available with the `fuzzing` feature.
+
+use crypto_types::{KeyPair, PrivateKey, PublicKey, AuthToken};
+use protocol::{Role, Signaling, InitiatorSignaling, ResponderSignaling, MAX_RESPONDERS};
+use tasks::Tasks;
+
+
+/// Feed arbitrary bytes to a new signaling instance with fixed keys.
+///
+/// Errors are ignored. This function must never panic, regardless of the
+/// input.
+#[doc(hidden)]
+pub fn fuzz_handle_message(role: Role, bytes: &[u8]) {
+    let private_key = PrivateKey::from_slice(&[0x01; 32]).expect("Invalid private key");
+    let keypair = KeyPair::from_private_key(private_key);
+    let mut signaling: Box<Signaling> = match role {
+        Role::Initiator => Box::new(InitiatorSignaling::new(
+            keypair,
+            Tasks(vec![]),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
+        )),
+        Role::Responder => Box::new(ResponderSignaling::new(
+            keypair,
+            PublicKey::from_slice(&[0x02; 32]).expect("Invalid public key"),
+            Some(AuthToken::from_slice(&[0x03; 32]).expect("Invalid auth token")),
+            None,
+            Tasks(vec![]),
+            None,
+        )),
+    };
+
+    let _ = signaling.handle_frame(bytes);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use boxes::OpenBox;
+    use protocol::{Cookie, Nonce};
+    use protocol::types::Address;
+    use protocol::csn::CombinedSequenceSnapshot;
+    use protocol::messages::{Message, ServerHello};
+
+    use super::*;
+
+    /// Return the bytes of a nonce from the server to an unknown client.
+    fn server_nonce_bytes() -> Vec<u8> {
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        nonce.into_bytes().to_vec()
+    }
+
+    #[test]
+    fn corpus_seeds() {
+        let mut corpus: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0; 23],
+            vec![0; 24],
+            vec![0xff; 64],
+        ];
+
+        // Nonce followed by garbage
+        let mut garbage = server_nonce_bytes();
+        garbage.extend(&[0xc1, 0x00, 0xde, 0xad]);
+        corpus.push(garbage);
+
+        // Nonce followed by a truncated map
+        let mut truncated = server_nonce_bytes();
+        truncated.extend(&[0x82, 0xa4, 0x74, 0x79, 0x70, 0x65]);
+        corpus.push(truncated);
+
+        // A valid server-hello message
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        let msg: Message = ServerHello::random().into_message();
+        corpus.push(OpenBox::<Message>::new(msg, nonce).encode().into_bytes());
+
+        for bytes in &corpus {
+            fuzz_handle_message(Role::Initiator, bytes);
+            fuzz_handle_message(Role::Responder, bytes);
+        }
+    }
+}
@@ -61,6 +61,8 @@ extern crate rust_sodium_sys;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "debug-tools")]
+extern crate serde_json;
 extern crate tokio_core;
 extern crate tokio_timer;
 extern crate websocket;
@@ -76,6 +78,8 @@ pub mod dep {
 mod boxes;
 mod crypto_types;
 pub mod errors;
+#[cfg(any(test, feature = "fuzzing"))]
+mod fuzzing;
 mod helpers;
 mod protocol;
 mod send_all;
@@ -85,17 +89,19 @@ mod test_helpers;
 
 // Rust imports
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Third party imports
 use data_encoding::HEXLOWER;
 use futures::{stream, Future, Stream, Sink};
-use futures::future::{self, Loop};
+use futures::future::{self, Either, Loop};
 use futures::sync::mpsc;
 use futures::sync::oneshot;
 use native_tls::TlsConnector;
@@ -112,20 +118,24 @@ use websocket::header::WebSocketProtocol;
 use websocket::message::{OwnedMessage, CloseData};
 
 // Re-exports
-pub use protocol::Role;
+pub use protocol::{Role, ClientIdentity, SessionSummary, ResetOptions, DroppedMessage, SignalingState, ServerHandshakeState};
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use fuzzing::fuzz_handle_message;
 
 /// Cryptography-related types like public/private keys.
 pub mod crypto {
     pub use crypto_types::{KeyPair, PublicKey, PrivateKey, AuthToken};
     pub use crypto_types::{public_key_from_hex_str, private_key_from_hex_str};
+    pub use crypto_types::verify_equal;
 }
 
 // Internal imports
 use boxes::{ByteBox};
-use crypto_types::{KeyPair, PublicKey, AuthToken};
+use crypto_types::{KeyPair, PublicKey, AuthToken, public_key_from_hex_str};
 use errors::{SaltyResult, SaltyError, SignalingResult, SignalingError, BuilderError};
 use helpers::libsodium_init;
-use protocol::{HandleAction, Signaling, InitiatorSignaling, ResponderSignaling};
+use protocol::{Common, HandleAction, HandshakePhase, Signaling, InitiatorSignaling, ResponderSignaling, CsnMode, UnknownFields, MAX_RESPONDERS, MAX_DROPPED_MESSAGES};
 use tasks::{Tasks, TaskMessage, BoxedTask};
 
 
@@ -157,6 +167,10 @@ pub struct SaltyClientBuilder {
     permanent_key: KeyPair,
     tasks: Vec<BoxedTask>,
     ping_interval: Option<Duration>,
+    common_options: CommonOptions,
+    max_responders: usize,
+    strict_protocol: bool,
+    allowed_responder_keys: Option<HashSet<PublicKey>>,
     server_public_permanent_key: Option<PublicKey>,
 }
 
@@ -167,6 +181,15 @@ impl SaltyClientBuilder {
             permanent_key,
             tasks: vec![],
             ping_interval: None,
+            common_options: CommonOptions {
+                csn_mode: CsnMode::default(),
+                unknown_fields: UnknownFields::default(),
+                subprotocol: SUBPROTOCOL.into(),
+                max_dropped_messages: MAX_DROPPED_MESSAGES,
+            },
+            max_responders: MAX_RESPONDERS,
+            strict_protocol: false,
+            allowed_responder_keys: None,
             server_public_permanent_key: None,
         }
     }
@@ -200,16 +223,102 @@ impl SaltyClientBuilder {
         self
     }
 
+    /// Accept incoming messages with reordered combined sequence numbers,
+    /// as long as they are within a window of the specified size.
+    ///
+    /// Messages whose combined sequence number has already been seen are
+    /// always rejected. The maximum window size is 64, larger values are
+    /// capped.
+    ///
+    /// Set the `window` argument to `None` or to 0 to disable the window.
+    ///
+    /// By default, the window is disabled and every incoming combined
+    /// sequence number must be higher than the previous one.
+    pub fn with_csn_window(mut self, window: Option<u8>) -> Self {
+        self.common_options.csn_mode = CsnMode::from_window(window.unwrap_or(0));
+        self
+    }
+
+    /// Limit the number of responders an initiator keeps track of.
+    ///
+    /// When a new responder exceeds this limit, the oldest responders that
+    /// have not sent any valid message yet are dropped. The value is capped
+    /// to the range 1..252.
+    ///
+    /// This setting only applies to initiators. By default, up to 252
+    /// responders (the full responder address range) are tracked.
+    pub fn with_max_responders(mut self, max_responders: usize) -> Self {
+        self.max_responders = cmp::min(cmp::max(max_responders, 1), MAX_RESPONDERS);
+        self
+    }
+
+    /// Reject incoming messages that contain unknown fields.
+    ///
+    /// By default, unknown fields are ignored, so that newer servers and
+    /// peers may add fields without breaking compatibility. Enable strict
+    /// mode to fail the signaling instead.
+    pub fn with_strict_fields(mut self, strict: bool) -> Self {
+        self.common_options.unknown_fields = if strict { UnknownFields::Reject } else { UnknownFields::Ignore };
+        self
+    }
+
+    /// Fail the signaling when receiving unexpected but harmless messages
+    /// from the server, e.g. a 'new-responder' message sent to a responder.
+    ///
+    /// This setting only applies to responders. By default, such messages
+    /// are ignored with a warning.
+    pub fn with_strict_protocol(mut self, strict: bool) -> Self {
+        self.strict_protocol = strict;
+        self
+    }
+
+    /// Use a custom WebSocket subprotocol instead of `v1.saltyrtc.org`.
+    ///
+    /// This is only useful for testing against custom server
+    /// implementations. The server must accept the specified subprotocol,
+    /// otherwise connecting fails.
+    pub fn with_subprotocol<S: Into<String>>(mut self, subprotocol: S) -> Self {
+        self.common_options.subprotocol = subprotocol.into();
+        self
+    }
+
+    /// Close the connection after the specified number of consecutive
+    /// messages from the same source had to be dropped.
+    ///
+    /// Messages are dropped (with a warning) if they come from a source we
+    /// don't accept messages from. A valid message from that source resets
+    /// the counter. By default, up to 16 consecutive messages are dropped.
+    pub fn with_max_dropped_messages(mut self, max_dropped_messages: usize) -> Self {
+        self.common_options.max_dropped_messages = max_dropped_messages;
+        self
+    }
+
+    /// Only accept responders whose public permanent key is in the
+    /// specified list.
+    ///
+    /// Responders presenting a different key in their 'token' message are
+    /// dropped with close code 3007 (Invalid Key). This setting only applies
+    /// to initiators that are not using a trusted responder key. By default,
+    /// all responders that know the auth token are accepted.
+    pub fn with_allowed_responder_keys<I>(mut self, keys: I) -> Self
+            where I: IntoIterator<Item = PublicKey> {
+        self.allowed_responder_keys = Some(keys.into_iter().collect());
+        self
+    }
+
     /// Create a new SaltyRTC initiator.
     pub fn initiator(self) -> Result<SaltyClient, BuilderError> {
         let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = InitiatorSignaling::new(
+        let mut signaling = InitiatorSignaling::new(
             self.permanent_key,
             tasks,
             None,
             self.server_public_permanent_key,
             self.ping_interval,
+            self.max_responders,
         );
+        self.common_options.apply(signaling.common_mut());
+        signaling.allowed_responder_keys = self.allowed_responder_keys;
         Ok(SaltyClient {
             signaling: Box::new(signaling),
         })
@@ -218,13 +327,15 @@ impl SaltyClientBuilder {
     /// Create a new SaltyRTC initiator with a trusted peer public key.
     pub fn initiator_trusted(self, responder_trusted_pubkey: PublicKey) -> Result<SaltyClient, BuilderError> {
         let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = InitiatorSignaling::new(
+        let mut signaling = InitiatorSignaling::new(
             self.permanent_key,
             tasks,
             Some(responder_trusted_pubkey),
             self.server_public_permanent_key,
             self.ping_interval,
+            self.max_responders,
         );
+        self.common_options.apply(signaling.common_mut());
         Ok(SaltyClient {
             signaling: Box::new(signaling),
         })
@@ -233,7 +344,7 @@ impl SaltyClientBuilder {
     /// Create a new SaltyRTC responder.
     pub fn responder(self, initiator_pubkey: PublicKey, auth_token: AuthToken) -> Result<SaltyClient, BuilderError> {
         let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = ResponderSignaling::new(
+        let mut signaling = ResponderSignaling::new(
             self.permanent_key,
             initiator_pubkey,
             Some(auth_token),
@@ -241,15 +352,32 @@ impl SaltyClientBuilder {
             tasks,
             self.ping_interval,
         );
+        self.common_options.apply(signaling.common_mut());
+        signaling.strict_protocol = self.strict_protocol;
         Ok(SaltyClient {
             signaling: Box::new(signaling),
         })
     }
 
+    /// Create a new SaltyRTC responder from the hex encoded initiator public
+    /// key and auth token.
+    ///
+    /// This is useful if both values are transferred together, e.g. in a
+    /// single QR code.
+    pub fn responder_from_hex(self, initiator_pubkey_hex: &str, auth_token_hex: &str) -> Result<SaltyClient, BuilderError> {
+        let to_builder_error = |e: SaltyError| match e {
+            SaltyError::Decode(msg) => BuilderError::Decode(msg),
+            other => BuilderError::Decode(other.to_string()),
+        };
+        let initiator_pubkey = public_key_from_hex_str(initiator_pubkey_hex).map_err(&to_builder_error)?;
+        let auth_token = AuthToken::from_hex_str(auth_token_hex).map_err(&to_builder_error)?;
+        self.responder(initiator_pubkey, auth_token)
+    }
+
     /// Create a new SaltyRTC responder with a trusted peer public key.
     pub fn responder_trusted(self, initiator_trusted_pubkey: PublicKey) -> Result<SaltyClient, BuilderError> {
         let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = ResponderSignaling::new(
+        let mut signaling = ResponderSignaling::new(
             self.permanent_key,
             initiator_trusted_pubkey,
             None,
@@ -257,12 +385,32 @@ impl SaltyClientBuilder {
             tasks,
             self.ping_interval,
         );
+        self.common_options.apply(signaling.common_mut());
+        signaling.strict_protocol = self.strict_protocol;
         Ok(SaltyClient {
             signaling: Box::new(signaling),
         })
     }
 }
 
+/// Builder options that apply to both roles.
+struct CommonOptions {
+    csn_mode: CsnMode,
+    unknown_fields: UnknownFields,
+    subprotocol: String,
+    max_dropped_messages: usize,
+}
+
+impl CommonOptions {
+    /// Store the options in the common signaling state.
+    fn apply(self, common: &mut Common) {
+        common.csn_mode = self.csn_mode;
+        common.unknown_fields = self.unknown_fields;
+        common.subprotocol = self.subprotocol;
+        common.max_dropped_messages = self.max_dropped_messages;
+    }
+}
+
 /// The SaltyRTC Client instance.
 ///
 /// To create an instance of this struct, use the
@@ -289,16 +437,76 @@ impl SaltyClient {
         self.signaling.role()
     }
 
+    /// Return the assigned client identity.
+    ///
+    /// The identity is `ClientIdentity::Unknown` until the server has
+    /// assigned an identity in the server handshake.
+    pub fn identity(&self) -> ClientIdentity {
+        self.signaling.identity()
+    }
+
+    /// Return whether the server handshake is done.
+    pub fn is_server_handshake_done(&self) -> bool {
+        self.signaling.is_server_handshake_done()
+    }
+
+    /// Return whether both the server and the peer handshake are done.
+    ///
+    /// Task messages can only be sent once this returns `true`.
+    pub fn is_handshake_done(&self) -> bool {
+        self.signaling.is_handshake_done()
+    }
+
+    /// Return the number of peers known to this client.
+    pub fn peer_count(&self) -> usize {
+        self.signaling.peer_count()
+    }
+
+    /// Return a summary of the current signaling session, e.g. for metrics.
+    pub fn summary(&self) -> SessionSummary {
+        self.signaling.summary()
+    }
+
     /// Return a reference to the auth token.
     pub fn auth_token(&self) -> Option<&AuthToken> {
         self.signaling.auth_token()
     }
 
+    /// Return whether the auth token has been consumed by the peer handshake.
+    ///
+    /// Once this returns `true`, the out-of-band auth token is no longer
+    /// needed and can be discarded.
+    pub fn auth_token_consumed(&self) -> bool {
+        self.signaling.auth_token_consumed()
+    }
+
     /// Return a reference to the initiator public key.
     pub fn initiator_pubkey(&self) -> &PublicKey {
         self.signaling.initiator_pubkey()
     }
 
+    /// Return the public permanent key of the server (if known).
+    ///
+    /// This is the key passed to
+    /// [`with_server_key`](struct.SaltyClientBuilder.html#method.with_server_key).
+    /// Once the server handshake is done, the server has proven ownership
+    /// of it.
+    pub fn server_permanent_key(&self) -> Option<PublicKey> {
+        self.signaling.server_permanent_key()
+    }
+
+    /// Return the public session key of the server (if known).
+    ///
+    /// This is the key received in the server-hello message.
+    pub fn server_session_key(&self) -> Option<PublicKey> {
+        self.signaling.server_session_key()
+    }
+
+    /// Return the WebSocket subprotocol used to connect to the server.
+    pub fn subprotocol(&self) -> &str {
+        &self.signaling.common().subprotocol
+    }
+
     /// Return a reference to the selected task.
     pub fn task(&self) -> Option<Arc<Mutex<BoxedTask>>> {
         self.signaling
@@ -307,11 +515,51 @@ impl SaltyClient {
             .clone()
     }
 
+    /// Drain the diagnostics for all messages that were dropped during
+    /// nonce validation since the last call, oldest first.
+    ///
+    /// Only the most recent entries are kept between calls.
+    pub fn drain_dropped_messages(&mut self) -> Vec<DroppedMessage> {
+        self.signaling.drain_dropped_messages()
+    }
+
+    /// Reset the client, so that it can be used for a new connection to
+    /// the server.
+    ///
+    /// The server handshake and, unless it has finished, the peer
+    /// handshake start over on the next connection. The `options` control
+    /// whether our cookies towards the server and the peer are kept. The
+    /// protocol requires fresh cookies, so only reuse them if the other
+    /// side expects that.
+    ///
+    /// This fails once the peer handshake has finished.
+    pub fn reset(&mut self, options: ResetOptions) -> SaltyResult<()> {
+        Ok(self.signaling.reset_with_options(options)?)
+    }
+
+    /// Replace our permanent keypair, e.g. to migrate to a new identity.
+    ///
+    /// This is only possible before the server handshake has started, for
+    /// example right after a [`reset`](#method.reset).
+    pub fn set_permanent_key(&mut self, keypair: KeyPair) -> SaltyResult<()> {
+        Ok(self.signaling.set_permanent_key(keypair)?)
+    }
+
     /// Handle an incoming message.
     fn handle_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
         self.signaling.handle_message(bbox)
     }
 
+    /// Handle a stalled handshake in the current handshake phase.
+    fn handle_timeout(&mut self) -> Vec<HandleAction> {
+        let phase = if self.signaling.is_server_handshake_done() {
+            HandshakePhase::Peer
+        } else {
+            HandshakePhase::Server
+        };
+        self.signaling.on_timeout(phase)
+    }
+
     /// Encrypt a task message.
     pub fn encrypt_task_message(&mut self, val: Value) -> SaltyResult<Vec<u8>> {
         trace!("Encrypting task message");
@@ -356,8 +604,26 @@ pub enum Event {
     /// Peer handshake is done.
     PeerHandshakeDone,
 
+    /// A new peer connected to the server.
+    ///
+    /// For initiators, this is a responder that may now start the peer
+    /// handshake.
+    NewPeer(ClientIdentity),
+
     /// An authenticated peer disconnected from the server.
+    ///
+    /// This event is no longer emitted and only kept for compatibility.
+    /// A `disconnected` message from the server results in a
+    /// `PeerDisconnected` event instead.
+    #[deprecated(note = "use `Event::PeerDisconnected` instead")]
     Disconnected(u8),
+
+    /// A peer disconnected from the server or was dropped.
+    ///
+    /// This is emitted when the server reports a disconnected peer, when the
+    /// initiator drops a responder and when a message to a responder in the
+    /// handshake could not be relayed by the server.
+    PeerDisconnected(ClientIdentity),
 }
 
 
@@ -386,6 +652,8 @@ pub enum CloseCode {
     NoSharedTask,
     /// Invalid key
     InvalidKey,
+    /// Timeout
+    Timeout,
     /// Other close code
     Other(u16),
 }
@@ -406,6 +674,7 @@ impl CloseCode {
             InitiatorCouldNotDecrypt => 3005,
             NoSharedTask => 3006,
             InvalidKey => 3007,
+            Timeout => 3008,
             Other(code) => code,
         }
     }
@@ -425,6 +694,7 @@ impl CloseCode {
             3005 => InitiatorCouldNotDecrypt,
             3006 => NoSharedTask,
             3007 => InvalidKey,
+            3008 => Timeout,
             code => Other(code),
         }
     }
@@ -497,8 +767,8 @@ pub fn connect(
     libsodium_init()?;
 
     // Parse URL
-    let path = salty.try_borrow()
-        .map(|client| HEXLOWER.encode(&client.initiator_pubkey().0))
+    let (path, subprotocol) = salty.try_borrow()
+        .map(|client| (HEXLOWER.encode(&client.initiator_pubkey().0), client.subprotocol().to_string()))
         .map_err(|_| SaltyError::Crash("Could not borrow SaltyClient instance".into()))?;
     let url = format!("wss://{}:{}/{}", host, port, path);
     let ws_url = match Url::parse(&url) {
@@ -509,17 +779,17 @@ pub fn connect(
     // Initialize WebSocket client
     let server = format!("{}:{}", host, port);
     let future = ClientBuilder::from_url(&ws_url)
-        .add_protocol(SUBPROTOCOL)
+        .add_protocol(subprotocol.clone())
         .async_connect_secure(tls_config, handle)
         .map_err(move |e: WebSocketError| SaltyError::Network(match e.cause() {
             Some(cause) => format!("Could not connect to server ({}): {}: {}", server, e, cause),
             None => format!("Could not connect to server ({}): {}", server, e),
         }))
-        .and_then(|(client, headers)| {
+        .and_then(move |(client, headers)| {
             // Verify that the correct subprotocol was chosen
             trace!("Websocket server headers: {:?}", headers);
             match headers.get::<WebSocketProtocol>() {
-                Some(proto) if proto.len() == 1 && proto[0] == SUBPROTOCOL => {
+                Some(proto) if proto.len() == 1 && proto[0] == subprotocol => {
                     Ok(client)
                 },
                 Some(proto) => {
@@ -629,6 +899,17 @@ fn preprocess_ws_message((decoded, client): (WsMessageDecoded, WsClient)) -> Sal
     Ok(PipelineAction::ByteBox((client, bbox)))
 }
 
+/// Log a signaling error that aborts the connection.
+///
+/// Errors that are not fatal may be resolved by reconnecting.
+fn log_signaling_error(e: &SignalingError) {
+    if e.is_fatal() {
+        error!("Fatal signaling error: {}", e);
+    } else {
+        error!("Signaling error, reconnecting may help: {}", e);
+    }
+}
+
 /// Do the server and peer handshake.
 ///
 /// This function returns a future. The future must be run in a Tokio reactor
@@ -642,17 +923,18 @@ pub fn do_handshake(
     event_tx: mpsc::UnboundedSender<Event>,
     timeout: Option<Duration>,
 ) -> impl Future<Item=WsClient, Error=SaltyError> {
+    // The whole handshake must be done before the deadline
+    let timer = Timer::default();
+    let deadline = timeout.map(|duration| Instant::now() + duration);
+
     // Main loop
-    let main_loop = future::loop_fn(client, move |client| {
+    future::loop_fn(client, move |client| {
 
         let salty = Rc::clone(&salty);
 
         // Take the next incoming message
         let event_tx = event_tx.clone();
-        client.into_future()
-
-            // Map errors to our custom error type
-            .map_err(|(e, _)| SaltyError::Network(format!("Could not receive message from server: {}", e)))
+        receive_before_deadline(client, &timer, deadline, Rc::clone(&salty))
 
             // Process incoming messages and convert them to a `WsMessageDecoded`.
             .and_then(|(msg_option, client)| {
@@ -677,7 +959,10 @@ pub fn do_handshake(
                 let handle_actions = match salty.deref().try_borrow_mut() {
                     Ok(mut s) => match s.handle_message(bbox) {
                         Ok(actions) => actions,
-                        Err(e) => return boxed!(future::err(e.into())),
+                        Err(e) => {
+                            log_signaling_error(&e);
+                            return boxed!(future::err(e.into()));
+                        },
                     },
                     Err(e) => return boxed!(future::err(SaltyError::Crash(
                         format!("Could not get mutable reference to SaltyClient: {}", e)
@@ -717,6 +1002,19 @@ pub fn do_handshake(
                                 late_error = Some(e);
                             }
                         },
+                        HandleAction::Disconnect(reason) => {
+                            debug!("<-- Enqueuing WebSocket close message to server");
+                            messages.push(OwnedMessage::Close(Some(CloseData {
+                                status_code: reason.as_number(),
+                                reason: reason.to_string(),
+                            })));
+                            let e = SaltyError::Protocol(format!("Closing connection to server: {}", reason));
+                            if late_error.is_some() {
+                                error!("Dropping error because another error happened previously: {}", e);
+                            } else {
+                                late_error = Some(e);
+                            }
+                        },
                     }
                 }
 
@@ -750,15 +1048,78 @@ pub fn do_handshake(
                     boxed!(future)
                 }
             })
-    });
+    })
+}
 
-    let timeout_duration = match timeout {
-        Some(duration) => duration,
-        None => return boxed!(main_loop),
+/// Receive the next message from the server during the handshake.
+///
+/// If a `deadline` is set and no message arrives in time, the handshake has
+/// stalled. The signaling is notified, the resulting close message is sent to
+/// the server and the future fails with a timeout error.
+fn receive_before_deadline(
+    client: WsClient,
+    timer: &Timer,
+    deadline: Option<Instant>,
+    salty: Rc<RefCell<SaltyClient>>,
+) -> BoxedFuture<(Option<OwnedMessage>, WsClient), SaltyError> {
+    let receive = client.into_future();
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return boxed!(receive.map_err(
+            |(e, _)| SaltyError::Network(format!("Could not receive message from server: {}", e))
+        )),
     };
+    let now = Instant::now();
+    let remaining = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+    boxed!(receive
+        .select2(timer.sleep(remaining))
+        .then(move |result| match result {
+            Ok(Either::A((received, _))) => boxed!(future::ok(received)),
+            Ok(Either::B((_, receive))) => match receive.into_inner() {
+                Some(client) => handle_handshake_timeout(client, &salty),
+                None => boxed!(future::err(SaltyError::Crash("Server message stream is gone".into()))),
+            },
+            Err(Either::A(((e, _), _))) => boxed!(future::err(
+                SaltyError::Network(format!("Could not receive message from server: {}", e))
+            )),
+            Err(Either::B((e, _))) => boxed!(future::err(
+                SaltyError::Crash(format!("Handshake timer failed: {}", e))
+            )),
+        }))
+}
 
-    let timer = Timer::default();
-    boxed!(timer.timeout(main_loop, timeout_duration))
+/// Send the messages produced by the signaling for a stalled handshake to
+/// the server, then fail with a timeout error.
+fn handle_handshake_timeout(
+    client: WsClient,
+    salty: &Rc<RefCell<SaltyClient>>,
+) -> BoxedFuture<(Option<OwnedMessage>, WsClient), SaltyError> {
+    let handle_actions = match salty.try_borrow_mut() {
+        Ok(mut s) => s.handle_timeout(),
+        Err(e) => return boxed!(future::err(SaltyError::Crash(
+            format!("Could not get mutable reference to SaltyClient: {}", e)
+        ))),
+    };
+
+    let mut messages = vec![];
+    for action in handle_actions {
+        match action {
+            HandleAction::Reply(bbox) => messages.push(OwnedMessage::Binary(bbox.into_bytes())),
+            HandleAction::Disconnect(reason) => {
+                debug!("<-- Enqueuing WebSocket close message to server");
+                messages.push(OwnedMessage::Close(Some(CloseData {
+                    status_code: reason.as_number(),
+                    reason: reason.to_string(),
+                })));
+            },
+            other => warn!("Ignoring action after handshake timeout: {:?}", other),
+        }
+    }
+
+    let outbox = stream::iter_ok::<_, WebSocketError>(messages);
+    boxed!(send_all::new(client, outbox)
+        .map_err(|e| SaltyError::Network(format!("Could not send message: {}", e)))
+        .and_then(|_| future::err::<(Option<OwnedMessage>, WsClient), _>(SaltyError::Timeout)))
 }
 
 /// Start the task loop.
@@ -828,7 +1189,10 @@ pub fn task_loop(
                         let handle_actions = match salty.deref().try_borrow_mut() {
                             Ok(mut s) => match s.handle_message(bbox) {
                                 Ok(actions) => actions,
-                                Err(e) => return boxed!(future::err(Err(e.into()))),
+                                Err(e) => {
+                                    log_signaling_error(&e);
+                                    return boxed!(future::err(Err(e.into())));
+                                },
                             },
                             Err(e) => return boxed!(future::err(Err(
                                 SaltyError::Crash(format!("Could not get mutable reference to SaltyClient: {}", e))
@@ -866,6 +1230,14 @@ pub fn task_loop(
                                 HandleAction::HandshakeError(_) => return boxed!(future::err(Err(
                                     SaltyError::Crash("Got HandleAction::HandshakeError in task loop".into())
                                 ))),
+                                HandleAction::Disconnect(reason) => {
+                                    debug!("<-- Enqueuing WebSocket close message to server");
+                                    out_messages.push(OwnedMessage::Close(Some(CloseData {
+                                        status_code: reason.as_number(),
+                                        reason: reason.to_string(),
+                                    })));
+                                    close_stream = true;
+                                },
                             }
                         }
 
@@ -1092,3 +1464,129 @@ pub fn task_loop(
     // Return reference to task and the task loop future
     Ok((task, task_loop))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use protocol::clock::MockClock;
+    use test_helpers::DummyTask;
+
+    const PUBKEY_HEX: &str = "133798235bc42d37ce009b4b202cfe08bfd133c8e6eea75037fabb88f01fd959";
+    const TOKEN_HEX: &str = "8bb6b6ae1497bf0288e6f82923e8875f2fdeab2ab6833e770182b35936232af9";
+
+    fn builder() -> SaltyClientBuilder {
+        SaltyClient::build(KeyPair::new()).add_task(Box::new(DummyTask::new(42)))
+    }
+
+    fn unwrap_err(result: Result<SaltyClient, BuilderError>) -> BuilderError {
+        match result {
+            Ok(_) => panic!("Expected builder error"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn responder_from_hex() {
+        let client = builder().responder_from_hex(PUBKEY_HEX, TOKEN_HEX).unwrap();
+        assert_eq!(client.role(), Role::Responder);
+        assert_eq!(client.initiator_pubkey(), &public_key_from_hex_str(PUBKEY_HEX).unwrap());
+        assert_eq!(client.auth_token(), Some(&AuthToken::from_hex_str(TOKEN_HEX).unwrap()));
+    }
+
+    #[test]
+    fn responder_from_hex_malformed() {
+        let err = unwrap_err(builder().responder_from_hex("1337", TOKEN_HEX));
+        assert_eq!(err, BuilderError::Decode("Invalid public key hex string".into()));
+
+        let err = unwrap_err(builder().responder_from_hex("xyz", TOKEN_HEX));
+        assert_eq!(err, BuilderError::Decode("Could not decode public key hex string".into()));
+
+        let err = unwrap_err(builder().responder_from_hex(PUBKEY_HEX, ""));
+        assert_eq!(err, BuilderError::Decode("Invalid auth token hex string".into()));
+    }
+
+    #[test]
+    fn responder_from_hex_missing_task() {
+        let err = unwrap_err(SaltyClient::build(KeyPair::new()).responder_from_hex(PUBKEY_HEX, TOKEN_HEX));
+        assert_eq!(err, BuilderError::MissingTask);
+    }
+
+    /// A stalled server handshake closes the connection with close code 3008.
+    #[test]
+    fn handle_timeout_server_handshake() {
+        let mut salty = builder().initiator().unwrap();
+        assert_eq!(salty.handle_timeout(), vec![HandleAction::Disconnect(CloseCode::Timeout)]);
+    }
+
+    /// The server permanent key is the one configured in the builder.
+    #[test]
+    fn server_keys() {
+        let server_ks = KeyPair::new();
+        let salty = builder()
+            .with_server_key(server_ks.public_key().clone())
+            .initiator()
+            .unwrap();
+        assert_eq!(salty.server_permanent_key().as_ref(), Some(server_ks.public_key()));
+        assert_eq!(salty.server_session_key(), None);
+
+        let salty = builder().initiator().unwrap();
+        assert_eq!(salty.server_permanent_key(), None);
+    }
+
+    /// A reset keeps the server cookie only if requested.
+    #[test]
+    fn reset() {
+        let mut salty = builder().initiator().unwrap();
+        salty.signaling.common_mut().identity = ClientIdentity::Initiator;
+        let cookie = salty.signaling.server().cookie_pair.ours.clone();
+
+        salty.reset(ResetOptions { reuse_server_cookie: true, ..Default::default() }).unwrap();
+        assert_eq!(salty.identity(), ClientIdentity::Unknown);
+        assert_eq!(salty.signaling.server().cookie_pair.ours, cookie);
+
+        salty.reset(ResetOptions::default()).unwrap();
+        assert_ne!(salty.signaling.server().cookie_pair.ours, cookie);
+    }
+
+    /// The permanent key can only be replaced before the server handshake.
+    #[test]
+    fn set_permanent_key() {
+        let mut salty = builder().initiator().unwrap();
+        let keypair = KeyPair::new();
+        let public_key = keypair.public_key().clone();
+        salty.set_permanent_key(keypair).unwrap();
+        assert_eq!(salty.initiator_pubkey(), &public_key);
+
+        salty.signaling.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
+        assert_eq!(
+            salty.set_permanent_key(KeyPair::new()),
+            Err(SaltyError::Protocol("Cannot replace the permanent key after the server handshake has started".into()))
+        );
+        assert_eq!(salty.initiator_pubkey(), &public_key);
+    }
+
+    /// Dropped messages can be drained by the application.
+    #[test]
+    fn drain_dropped_messages() {
+        let mut salty = builder().initiator().unwrap();
+        salty.signaling.common_mut().clock = Box::new(MockClock::new(1000));
+        assert_eq!(salty.drain_dropped_messages(), vec![]);
+
+        // Messages from the initiator are dropped by an initiator
+        let mut bytes = vec![0; 32];
+        bytes[16] = 0x01; // Source
+        bytes[23] = 0x01; // Sequence number
+        let bbox = ByteBox::from_slice(&bytes).unwrap();
+        assert_eq!(salty.handle_message(bbox), Ok(vec![]));
+
+        assert_eq!(salty.drain_dropped_messages(), vec![DroppedMessage {
+            source: 0x01,
+            reason: "Bad source: 0x01 (our identity is unknown)".into(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1000),
+        }]);
+        assert_eq!(salty.drain_dropped_messages(), vec![]);
+    }
+}
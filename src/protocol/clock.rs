@@ -0,0 +1,80 @@
+//! Time sources.
+//!
+//! The signaling never reads the wall clock directly. Instead, it asks a
+//! [`Clock`](trait.Clock.html), so that time based behavior can be tested
+//! deterministically.
+
+use std::time::SystemTime;
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+
+/// A source of the current time.
+pub(crate) trait Clock {
+    /// Return the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// A clock returning the system time.
+///
+/// This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only advances when told to.
+///
+/// Clones share the same time, so a test can keep a clone to control the
+/// clock after handing it to a signaling instance.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct MockClock {
+    now: Rc<Cell<SystemTime>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Create a new `MockClock`, starting at the specified number of seconds
+    /// since the UNIX epoch.
+    pub(crate) fn new(secs: u64) -> Self {
+        MockClock {
+            now: Rc::new(Cell::new(UNIX_EPOCH + Duration::from_secs(secs))),
+        }
+    }
+
+    /// Advance the clock by the specified duration.
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_advance() {
+        let clock = MockClock::new(1000);
+        let handle = clock.clone();
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1000));
+        handle.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(1_001_500));
+    }
+}
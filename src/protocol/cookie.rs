@@ -97,6 +97,22 @@ impl CookiePair {
             theirs: None,
         }
     }
+
+    /// Create a new [`CookiePair`](struct.CookiePair.html) where our cookie
+    /// is guaranteed to differ from the specified cookie.
+    ///
+    /// This should be used whenever the peer cookie is already known at the
+    /// time the pair is created.
+    pub(crate) fn new_distinct_from(other: &Cookie) -> Self {
+        let mut ours = Cookie::random();
+        while ours == *other {
+            ours = Cookie::random();
+        }
+        CookiePair {
+            ours,
+            theirs: None,
+        }
+    }
 }
 
 
@@ -119,6 +135,17 @@ mod tests {
         assert_eq!(cookies.len(), 100);
     }
 
+    /// A cookie pair created from a known cookie never uses that cookie.
+    #[test]
+    fn cookie_pair_distinct_from() {
+        for _ in 0..100 {
+            let other = Cookie::random();
+            let pair = CookiePair::new_distinct_from(&other);
+            assert_ne!(pair.ours, other);
+            assert_eq!(pair.theirs, None);
+        }
+    }
+
     /// The cookie serializes to the contained raw bytes.
     #[test]
     fn cookie_serialize() {
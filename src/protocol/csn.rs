@@ -3,6 +3,10 @@
 //! This module handles the overflow checking of the 48 bit combined sequence
 //! number (CSN) in the SaltyRTC nonce consisting of the 32 bit sequence number
 //! and the 16 bit overflow number.
+//!
+//! On the wire, the overflow number is followed by the sequence number, both
+//! encoded as big endian unsigned integers. The resulting 6 bytes are equal to
+//! the big endian encoding of the 48 bit combined sequence number.
 
 use std::cmp;
 
@@ -176,12 +180,64 @@ impl cmp::PartialOrd<CombinedSequence> for CombinedSequenceSnapshot {
 }
 
 
+/// The maximum size of the CSN acceptance window.
+pub(crate) const MAX_CSN_WINDOW: u8 = 64;
+
+/// The mode used to validate incoming combined sequence numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CsnMode {
+    /// Every incoming CSN must be greater than the previous one.
+    ///
+    /// This is the default.
+    Strict,
+    /// Incoming CSNs may arrive out of order, as long as they're not older
+    /// than the specified number of CSNs below the highest CSN received so
+    /// far. CSNs that have already been received are still rejected.
+    Window(u8),
+}
+
+impl CsnMode {
+    /// Create a new `CsnMode` from the specified window size.
+    ///
+    /// A window size of 0 results in strict mode. Window sizes larger than
+    /// [`MAX_CSN_WINDOW`](constant.MAX_CSN_WINDOW.html) are capped.
+    pub(crate) fn from_window(size: u8) -> Self {
+        match size {
+            0 => CsnMode::Strict,
+            s if s > MAX_CSN_WINDOW => CsnMode::Window(MAX_CSN_WINDOW),
+            s => CsnMode::Window(s),
+        }
+    }
+}
+
+impl Default for CsnMode {
+    fn default() -> Self {
+        CsnMode::Strict
+    }
+}
+
+/// The reason why an incoming CSN was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CsnRejection {
+    /// The CSN is equal to the highest CSN received so far.
+    NotIncremented,
+    /// The CSN is lower than the highest CSN received so far.
+    Lower,
+    /// The CSN is within the acceptance window, but has already been received.
+    Replayed,
+}
+
+
 /// A pair of a [`CombinedSequence`](struct.CombinedSequence.html) and a
 /// [`CombinedSequenceSnapshot`](struct.CombinedSequenceSnapshot.html).
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct CombinedSequencePair {
     pub(crate) ours: CombinedSequence,
     pub(crate) theirs: Option<CombinedSequenceSnapshot>,
+    /// Bitmap of the received CSNs below the highest CSN (`theirs`).
+    ///
+    /// The least significant bit represents `theirs` itself.
+    received: u64,
 }
 
 impl CombinedSequencePair {
@@ -190,6 +246,47 @@ impl CombinedSequencePair {
         CombinedSequencePair {
             ours: CombinedSequence::random(),
             theirs: None,
+            received: 0,
+        }
+    }
+
+    /// Validate and store an incoming CSN of the peer.
+    ///
+    /// If no CSN has been stored so far, the CSN is always accepted.
+    pub(crate) fn update_theirs(&mut self, csn: &CombinedSequenceSnapshot, mode: CsnMode)
+                                -> Result<(), CsnRejection> {
+        let highest = match self.theirs {
+            Some(ref theirs) => theirs.combined_sequence_number(),
+            None => {
+                self.theirs = Some(csn.clone());
+                self.received = 1;
+                return Ok(());
+            },
+        };
+        let current = csn.combined_sequence_number();
+
+        if current > highest {
+            let shift = current - highest;
+            self.received = if shift >= 64 { 1 } else { (self.received << shift) | 1 };
+            self.theirs = Some(csn.clone());
+            return Ok(());
+        }
+        if current == highest {
+            return Err(CsnRejection::NotIncremented);
+        }
+
+        let offset = highest - current;
+        match mode {
+            CsnMode::Window(size) if offset < u64::from(size) => {
+                let bit = 1u64 << offset;
+                if self.received & bit != 0 {
+                    Err(CsnRejection::Replayed)
+                } else {
+                    self.received |= bit;
+                    Ok(())
+                }
+            },
+            _ => Err(CsnRejection::Lower),
         }
     }
 }
@@ -264,4 +361,94 @@ mod tests {
             ref other => panic!("Wrong error type: {:?}", other),
         };
     }
+
+    /// Snapshots are ordered by the full 48 bit combined sequence number,
+    /// so a higher overflow number always wins over the sequence number.
+    #[test]
+    fn snapshot_ordering_across_overflow() {
+        let before = CombinedSequenceSnapshot::new(0, ::std::u32::MAX);
+        let after = CombinedSequenceSnapshot::new(1, 0);
+        assert!(before < after);
+        assert!(after > before);
+        assert_eq!(before.cmp(&after), cmp::Ordering::Less);
+
+        let low_sequence_high_overflow = CombinedSequenceSnapshot::new(2, 1);
+        let high_sequence_low_overflow = CombinedSequenceSnapshot::new(1, ::std::u32::MAX);
+        assert!(high_sequence_low_overflow < low_sequence_high_overflow);
+
+        assert_eq!(CombinedSequenceSnapshot::new(1, 7), CombinedSequenceSnapshot::new(1, 7));
+        assert_ne!(CombinedSequenceSnapshot::new(1, 7), CombinedSequenceSnapshot::new(0, 7));
+    }
+
+    /// Comparing a `CombinedSequence` with a snapshot uses the same ordering.
+    #[test]
+    fn sequence_snapshot_ordering_across_overflow() {
+        let csn = CombinedSequence::new(0, ::std::u32::MAX);
+        assert!(csn < CombinedSequenceSnapshot::new(1, 0));
+        assert!(CombinedSequenceSnapshot::new(1, 0) > csn);
+        assert!(csn == CombinedSequenceSnapshot::new(0, ::std::u32::MAX));
+    }
+
+    fn pair_with_theirs(overflow: u16, sequence: u32) -> CombinedSequencePair {
+        let mut pair = CombinedSequencePair::new();
+        pair.update_theirs(&CombinedSequenceSnapshot::new(overflow, sequence), CsnMode::Strict).unwrap();
+        pair
+    }
+
+    #[test]
+    fn csn_mode_from_window() {
+        assert_eq!(CsnMode::from_window(0), CsnMode::Strict);
+        assert_eq!(CsnMode::from_window(8), CsnMode::Window(8));
+        assert_eq!(CsnMode::from_window(200), CsnMode::Window(MAX_CSN_WINDOW));
+        assert_eq!(CsnMode::default(), CsnMode::Strict);
+    }
+
+    /// In strict mode, any reordered CSN is rejected.
+    #[test]
+    fn update_theirs_strict_reordered() {
+        let mode = CsnMode::Strict;
+        let mut pair = pair_with_theirs(0, 10);
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 12), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 11), mode), Err(CsnRejection::Lower));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 12), mode), Err(CsnRejection::NotIncremented));
+        assert_eq!(pair.theirs, Some(CombinedSequenceSnapshot::new(0, 12)));
+    }
+
+    /// In window mode, reordered CSNs are accepted exactly once.
+    #[test]
+    fn update_theirs_window_reordered() {
+        let mode = CsnMode::Window(4);
+        let mut pair = pair_with_theirs(0, 10);
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 13), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 11), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 12), mode), Ok(()));
+        assert_eq!(pair.theirs, Some(CombinedSequenceSnapshot::new(0, 13)));
+
+        // Replays are rejected
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 10), mode), Err(CsnRejection::Replayed));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 11), mode), Err(CsnRejection::Replayed));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 13), mode), Err(CsnRejection::NotIncremented));
+    }
+
+    /// In window mode, CSNs older than the window are rejected.
+    #[test]
+    fn update_theirs_window_too_old() {
+        let mode = CsnMode::Window(4);
+        let mut pair = pair_with_theirs(0, 10);
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 20), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 16), mode), Err(CsnRejection::Lower));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, 17), mode), Ok(()));
+    }
+
+    /// The window also works across the sequence number overflow.
+    #[test]
+    fn update_theirs_window_overflow() {
+        let mode = CsnMode::Window(8);
+        let mut pair = pair_with_theirs(0, ::std::u32::MAX - 1);
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(1, 1), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, ::std::u32::MAX), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(1, 0), mode), Ok(()));
+        assert_eq!(pair.update_theirs(&CombinedSequenceSnapshot::new(0, ::std::u32::MAX - 1), mode),
+                   Err(CsnRejection::Replayed));
+    }
 }
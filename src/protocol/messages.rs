@@ -16,11 +16,27 @@ use crypto_types::{PublicKey, SignedKeys};
 use errors::{SignalingError, SignalingResult};
 
 use ::CloseCode;
-use ::protocol::{Address, Cookie};
+use ::protocol::{Address, ClientIdentity, Cookie};
 use ::protocol::send_error::SendErrorId;
 use ::tasks::Tasks;
 
 
+/// How unknown fields in incoming messages are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnknownFields {
+    /// Ignore unknown fields (forward compatible).
+    Ignore,
+    /// Reject messages containing unknown fields.
+    Reject,
+}
+
+impl Default for UnknownFields {
+    fn default() -> Self {
+        UnknownFields::Ignore
+    }
+}
+
+
 /// The `Message` enum contains all possible message types that may be used
 /// during the handshake in the SaltyRTC protocol.
 ///
@@ -63,8 +79,72 @@ pub(crate) enum Message {
 
 impl Message {
     /// Decode a message from msgpack bytes.
+    ///
+    /// If decoding fails, the error message will contain the type of the
+    /// message (if present).
     pub(crate) fn from_msgpack(bytes: &[u8]) -> SignalingResult<Self> {
-        Ok(rmps::from_slice(bytes)?)
+        rmps::from_slice(bytes).map_err(|e| {
+            match Self::peek_type(bytes) {
+                Some(ref msg_type) if !Self::all_type_names().contains(&msg_type.as_str()) => {
+                    SignalingError::Decode(format!(
+                        "Unknown message type \"{}\" (known types are: {})",
+                        msg_type, Self::all_type_names().join(", "),
+                    ))
+                },
+                Some(msg_type) => SignalingError::Decode(
+                    format!("Could not decode '{}' message: {}", msg_type, e)
+                ),
+                None => e.into(),
+            }
+        })
+    }
+
+    /// Return the names of all fields allowed in a message of the specified
+    /// type, excluding the `type` field itself.
+    fn field_names(msg_type: &str) -> &'static [&'static str] {
+        match msg_type {
+            "client-hello" | "server-hello" | "token" | "key" => &["key"],
+            "client-auth" => &["your_cookie", "subprotocols", "ping_interval", "your_key"],
+            "server-auth" => &["your_cookie", "signed_keys", "responders", "initiator_connected"],
+            "new-responder" | "send-error" | "disconnected" => &["id"],
+            "drop-responder" => &["id", "reason"],
+            "auth" => &["your_cookie", "tasks", "task", "data"],
+            "close" => &["reason"],
+            _ => &[],
+        }
+    }
+
+    /// Make sure that the msgpack bytes of a message of the specified type
+    /// do not contain any unknown fields.
+    pub(crate) fn reject_unknown_fields(bytes: &[u8], msg_type: &str) -> SignalingResult<()> {
+        let pairs = match rmps::from_slice::<Value>(bytes) {
+            Ok(Value::Map(pairs)) => pairs,
+            _ => return Err(SignalingError::Decode(
+                format!("Could not decode '{}' message as map", msg_type)
+            )),
+        };
+        let field_names = Self::field_names(msg_type);
+        for (key, _) in pairs {
+            match key.as_str() {
+                Some("type") => {},
+                Some(name) if field_names.contains(&name) => {},
+                _ => return Err(SignalingError::InvalidMessage(
+                    format!("Unknown field {} in '{}' message", key, msg_type)
+                )),
+            }
+        }
+        Ok(())
+    }
+
+    /// Try to extract the `type` field from msgpack bytes without decoding
+    /// the entire message.
+    pub(crate) fn peek_type(bytes: &[u8]) -> Option<String> {
+        match rmps::from_slice::<Value>(bytes) {
+            Ok(Value::Map(pairs)) => pairs.into_iter()
+                .find(|&(ref k, _)| k.as_str() == Some("type"))
+                .and_then(|(_, v)| v.as_str().map(String::from)),
+            _ => None,
+        }
     }
 
     /// Convert this message to msgpack bytes.
@@ -72,6 +152,19 @@ impl Message {
         rmps::to_vec_named(&self).expect("Serialization failed")
     }
 
+    /// Convert this message to pretty printed JSON.
+    ///
+    /// Binary fields (e.g. keys and cookies) are encoded as lowercase hex
+    /// strings. This is only meant for debugging, the output format is not
+    /// stable.
+    #[cfg(feature = "debug-tools")]
+    pub(crate) fn to_json(&self) -> String {
+        let value: Value = rmps::from_slice(&self.to_msgpack())
+            .expect("Could not decode serialized message");
+        ::serde_json::to_string_pretty(&msgpack_to_json(value))
+            .expect("JSON serialization failed")
+    }
+
     /// Return the type of the contained message.
     pub(crate) fn get_type(&self) -> &'static str {
         match *self {
@@ -93,6 +186,84 @@ impl Message {
             Message::Close(_) => "close",
         }
     }
+
+    /// Return whether this message is sent by the server to a client.
+    pub(crate) fn is_server_to_client(&self) -> bool {
+        match *self {
+            Message::ServerHello(_) |
+            Message::ServerAuth(_) |
+            Message::NewInitiator(_) |
+            Message::NewResponder(_) |
+            Message::SendError(_) |
+            Message::Disconnected(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Return whether this message is exchanged between clients.
+    pub(crate) fn is_client_to_client(&self) -> bool {
+        match *self {
+            Message::Token(_) |
+            Message::Key(_) |
+            Message::Auth(_) |
+            Message::Close(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Return the type names of all messages that can be decoded.
+    pub(crate) fn all_type_names() -> &'static [&'static str] {
+        &[
+            // Client to server and server to client messages
+            "client-hello",
+            "server-hello",
+            "client-auth",
+            "server-auth",
+            "new-initiator",
+            "new-responder",
+            "drop-responder",
+            "send-error",
+            "disconnected",
+
+            // Client to client messages
+            "token",
+            "key",
+            "auth",
+            "close",
+        ]
+    }
+}
+
+/// Convert a msgpack value to a JSON value, encoding binary data as hex.
+#[cfg(feature = "debug-tools")]
+fn msgpack_to_json(value: Value) -> ::serde_json::Value {
+    use data_encoding::HEXLOWER;
+    use serde_json::{Map, Number, Value as JsonValue};
+    match value {
+        Value::Nil => JsonValue::Null,
+        Value::Boolean(b) => JsonValue::Bool(b),
+        Value::Integer(i) => match (i.as_u64(), i.as_i64()) {
+            (Some(u), _) => JsonValue::Number(u.into()),
+            (None, Some(i)) => JsonValue::Number(i.into()),
+            (None, None) => JsonValue::Null,
+        },
+        Value::F32(f) => Number::from_f64(f64::from(f)).map_or(JsonValue::Null, JsonValue::Number),
+        Value::F64(f) => Number::from_f64(f).map_or(JsonValue::Null, JsonValue::Number),
+        Value::String(s) => JsonValue::String(s.into_str().unwrap_or_else(|| "<invalid utf-8>".into())),
+        Value::Binary(bytes) | Value::Ext(_, bytes) => JsonValue::String(HEXLOWER.encode(&bytes)),
+        Value::Array(values) => JsonValue::Array(values.into_iter().map(msgpack_to_json).collect()),
+        Value::Map(pairs) => {
+            let mut map = Map::new();
+            for (key, val) in pairs {
+                let key = match key {
+                    Value::String(s) => s.into_str().unwrap_or_else(|| "<invalid utf-8>".into()),
+                    other => other.to_string(),
+                };
+                map.insert(key, msgpack_to_json(val));
+            }
+            JsonValue::Object(map)
+        },
+    }
 }
 
 /// Implement conversion traits to wrap a type in a `Message`.
@@ -121,6 +292,7 @@ impl_message_wrapping!(NewInitiator, Message::NewInitiator);
 impl_message_wrapping!(NewResponder, Message::NewResponder);
 impl_message_wrapping!(DropResponder, Message::DropResponder);
 impl_message_wrapping!(SendError, Message::SendError);
+impl_message_wrapping!(Disconnected, Message::Disconnected);
 impl_message_wrapping!(Token, Message::Token);
 impl_message_wrapping!(Key, Message::Key);
 impl_message_wrapping!(Auth, Message::Auth);
@@ -230,6 +402,20 @@ pub(crate) struct NewResponder {
     pub(crate) id: Address,
 }
 
+impl NewResponder {
+    /// Return the identity of the new responder.
+    ///
+    /// If the `id` field does not contain a valid responder address
+    /// (0x02..0xff), `None` is returned.
+    pub(crate) fn identity(&self) -> Option<ClientIdentity> {
+        if self.id.is_responder() {
+            Some(ClientIdentity::Responder(self.id.0))
+        } else {
+            None
+        }
+    }
+}
+
 
 #[allow(dead_code)]
 pub(crate) enum DropReason {
@@ -237,6 +423,7 @@ pub(crate) enum DropReason {
     InternalError,
     DroppedByInitiator,
     InitiatorCouldNotDecrypt,
+    InvalidKey,
 }
 
 impl Into<u16> for DropReason {
@@ -247,6 +434,7 @@ impl Into<u16> for DropReason {
             InternalError => 3002,
             DroppedByInitiator => 3004,
             InitiatorCouldNotDecrypt => 3005,
+            InvalidKey => 3007,
         }
     }
 }
@@ -474,8 +662,211 @@ impl Close {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::*;
 
+    /// Build a msgpack map with a single `type` entry.
+    fn msgpack_with_type(msg_type: &str) -> Vec<u8> {
+        let val = Value::Map(vec![(Value::from("type"), Value::from(msg_type))]);
+        rmps::to_vec_named(&val).unwrap()
+    }
+
+    /// Return one message of every type, with all optional fields set.
+    fn all_messages() -> Vec<Message> {
+        let key = PublicKey::from_slice(&[0x42; 32]).unwrap();
+        let cookie = Cookie::new([0xab; 16]);
+        let mut data = HashMap::new();
+        data.insert("foo.bar.baz".to_string(), None);
+        let messages = vec![
+            ClientHello::new(key.clone()).into_message(),
+            ServerHello::new(key.clone()).into_message(),
+            ClientAuth {
+                your_cookie: cookie.clone(),
+                subprotocols: vec!["v1.saltyrtc.org".into()],
+                ping_interval: 0,
+                your_key: Some(key.clone()),
+            }.into_message(),
+            ServerAuth {
+                your_cookie: cookie.clone(),
+                signed_keys: Some(SignedKeys::new([0x11; 80])),
+                responders: Some(vec![Address(2)]),
+                initiator_connected: Some(true),
+            }.into_message(),
+            NewInitiator.into_message(),
+            NewResponder { id: Address(2) }.into_message(),
+            DropResponder::with_reason(Address(2), DropReason::ProtocolError).into_message(),
+            SendError { id: SendErrorId::from_slice(&[1, 2, 0, 0, 0, 0, 0, 1]).unwrap() }.into_message(),
+            Disconnected::new(Address(2)).into_message(),
+            Token { key: key.clone() }.into_message(),
+            Key { key: key.clone() }.into_message(),
+            Auth {
+                your_cookie: cookie.clone(),
+                tasks: Some(vec!["foo.bar.baz".into()]),
+                task: Some("foo.bar.baz".into()),
+                data,
+            }.into_message(),
+            Close::new(3001).into_message(),
+        ];
+
+        // Adding a message type breaks this match, as a reminder to add the
+        // new type to the list above.
+        for msg in &messages {
+            match *msg {
+                Message::ClientHello(_) | Message::ServerHello(_) | Message::ClientAuth(_) |
+                Message::ServerAuth(_) | Message::NewInitiator(_) | Message::NewResponder(_) |
+                Message::DropResponder(_) | Message::SendError(_) | Message::Disconnected(_) |
+                Message::Token(_) | Message::Key(_) | Message::Auth(_) | Message::Close(_) => {},
+            }
+        }
+        messages
+    }
+
+    #[test]
+    #[cfg(feature = "debug-tools")]
+    fn server_auth_to_json() {
+        let cookie = Cookie::new([0xab; 16]);
+        let msg = ServerAuth::for_initiator(cookie, None, vec![Address(2), Address(3)]).into_message();
+        let json = msg.to_json();
+        assert!(json.contains("\"type\": \"server-auth\""), "{}", json);
+        assert!(json.contains(&format!("\"your_cookie\": \"{}\"", "ab".repeat(16))), "{}", json);
+        assert!(json.contains("\"responders\""), "{}", json);
+        assert!(!json.contains("signed_keys"), "{}", json);
+    }
+
+    #[test]
+    fn decode_unknown_type() {
+        let err = Message::from_msgpack(&msgpack_with_type("foo")).unwrap_err();
+        match err {
+            SignalingError::Decode(msg) => {
+                assert!(msg.starts_with("Unknown message type \"foo\" (known types are: "), "{}", msg);
+                assert!(msg.contains("server-hello"));
+            },
+            other => panic!("Wrong error type: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_malformed_known_type() {
+        let err = Message::from_msgpack(&msgpack_with_type("server-hello")).unwrap_err();
+        match err {
+            SignalingError::Decode(msg) => assert!(msg.starts_with("Could not decode 'server-hello' message: "), "{}", msg),
+            other => panic!("Wrong error type: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_without_type() {
+        let err = Message::from_msgpack(&[0x80]).unwrap_err();
+        match err {
+            SignalingError::Decode(msg) => assert!(msg.starts_with("Could not decode msgpack data: "), "{}", msg),
+            other => panic!("Wrong error type: {:?}", other),
+        }
+    }
+
+    /// The `signed_keys` field must contain exactly 80 bytes (two public
+    /// keys plus the MAC). Other lengths are rejected when decoding, before
+    /// reaching the crypto layer.
+    #[test]
+    fn decode_signed_keys_length() {
+        let server_auth = |signed_keys: Vec<u8>| {
+            let msg = ServerAuth::for_initiator(Cookie::random(), None, vec![]).into_message();
+            let mut pairs = match rmps::from_slice(&msg.to_msgpack()).unwrap() {
+                Value::Map(pairs) => pairs,
+                other => panic!("Expected a map, got {:?}", other),
+            };
+            pairs.push((Value::from("signed_keys"), Value::Binary(signed_keys)));
+            rmps::to_vec_named(&Value::Map(pairs)).unwrap()
+        };
+
+        match Message::from_msgpack(&server_auth(vec![7; 80])).unwrap() {
+            Message::ServerAuth(msg) => assert_eq!(msg.signed_keys, Some(SignedKeys::new([7; 80]))),
+            other => panic!("Expected ServerAuth, got {:?}", other),
+        }
+        for &len in &[0, 79, 81] {
+            match Message::from_msgpack(&server_auth(vec![7; len])).unwrap_err() {
+                SignalingError::Decode(msg) => assert!(msg.starts_with("Could not decode 'server-auth' message: "), "{}", msg),
+                other => panic!("Wrong error type: {:?}", other),
+            }
+        }
+    }
+
+    /// Unknown fields must be ignored for forward compatibility.
+    #[test]
+    fn decode_unknown_field() {
+        let msg = ServerAuth::for_initiator(Cookie::random(), None, vec![Address(2)]).into_message();
+        let mut pairs = match rmps::from_slice(&msg.to_msgpack()).unwrap() {
+            Value::Map(pairs) => pairs,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        pairs.push((Value::from("future_field"), Value::from(42)));
+        let bytes = rmps::to_vec_named(&Value::Map(pairs)).unwrap();
+
+        assert_eq!(Message::from_msgpack(&bytes).unwrap(), msg);
+        assert_eq!(
+            Message::reject_unknown_fields(&bytes, "server-auth"),
+            Err(SignalingError::InvalidMessage("Unknown field \"future_field\" in 'server-auth' message".into()))
+        );
+    }
+
+    /// All fields of known messages are accepted when rejecting unknown fields.
+    #[test]
+    fn reject_unknown_fields_known() {
+        let messages = vec![
+            ClientHello::random().into_message(),
+            ServerAuth::for_initiator(Cookie::random(), None, vec![Address(2)]).into_message(),
+            DropResponder::with_reason(Address(3), DropReason::ProtocolError).into_message(),
+            Message::NewInitiator(NewInitiator),
+            Message::Close(Close::new(3001)),
+        ];
+        for msg in messages {
+            assert_eq!(Message::reject_unknown_fields(&msg.to_msgpack(), msg.get_type()), Ok(()));
+        }
+    }
+
+    /// The allowed field names of every message type are exactly the fields
+    /// that are serialized when all optional fields are set.
+    #[test]
+    fn field_names() {
+        for msg in all_messages() {
+            let msg_type = msg.get_type();
+            let fields: HashSet<String> = match rmps::from_slice(&msg.to_msgpack()).unwrap() {
+                Value::Map(pairs) => pairs.into_iter()
+                    .filter_map(|(key, _)| key.as_str().map(String::from))
+                    .filter(|key| key != "type")
+                    .collect(),
+                other => panic!("Expected a map, got {:?}", other),
+            };
+            let expected: HashSet<String> = Message::field_names(msg_type).iter()
+                .map(|name| name.to_string())
+                .collect();
+            assert_eq!(fields, expected, "Field names of '{}' message", msg_type);
+        }
+    }
+
+    /// Every message type is listed exactly once in `all_type_names` and
+    /// survives a msgpack round trip.
+    #[test]
+    fn all_type_names() {
+        let messages = all_messages();
+        let types: HashSet<&str> = messages.iter().map(Message::get_type).collect();
+        let names: HashSet<&str> = Message::all_type_names().iter().cloned().collect();
+        assert_eq!(types.len(), messages.len());
+        assert_eq!(names.len(), Message::all_type_names().len());
+        assert_eq!(types, names);
+        for msg in messages {
+            assert_eq!(Message::from_msgpack(&msg.to_msgpack()), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn new_responder_identity() {
+        assert_eq!(NewResponder { id: Address(0x00) }.identity(), None);
+        assert_eq!(NewResponder { id: Address(0x01) }.identity(), None);
+        assert_eq!(NewResponder { id: Address(0x02) }.identity(), Some(ClientIdentity::Responder(0x02)));
+        assert_eq!(NewResponder { id: Address(0xff) }.identity(), Some(ClientIdentity::Responder(0xff)));
+    }
+
     #[test]
     /// Verify that a message is correctly serialized, internally tagged.
     fn test_encode_message() {
@@ -567,6 +958,19 @@ mod tests {
                    .add_task("foo.bar.baz", None)
                    .build().unwrap());
         roundtrip!(close, Close::new(3003));
+        roundtrip!(auth_initiator_nested_data, ResponderAuthBuilder::new(Cookie::random())
+                   .add_task("foo.bar.baz", Some({
+                       let mut data = HashMap::new();
+                       data.insert("exclude".to_string(), Value::Array(vec![Value::from(1), Value::from(2)]));
+                       data.insert("handover".to_string(), Value::Boolean(true));
+                       data.insert("nested".to_string(), Value::Map(vec![
+                           (Value::from("max_packet_size"), Value::from(16384)),
+                           (Value::from("name"), Value::Binary(vec![0x00, 0xff])),
+                       ]));
+                       data
+                   }))
+                   .add_task("other.task", None)
+                   .build().unwrap());
     }
 
     mod auth {
@@ -8,16 +8,19 @@
 //! All peer related state is contained in the [context
 //! structs](context/index.html), depending on the role.
 
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::mem;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use boxes::{ByteBox, OpenBox};
-use crypto::{KeyPair, AuthToken, PublicKey};
+use crypto::{KeyPair, AuthToken, PublicKey, verify_equal};
 use errors::{SignalingError, SaltyError, SignalingResult};
 use rmpv::{Value};
 
+pub(crate) mod clock;
 pub(crate) mod context;
 pub(crate) mod cookie;
 pub(crate) mod csn;
@@ -31,21 +34,38 @@ pub(crate) mod types;
 
 use ::{Event, CloseCode};
 use ::tasks::{Tasks, BoxedTask, TaskMessage};
+use self::clock::{Clock, SystemClock};
 use self::context::{PeerContext, ServerContext, InitiatorContext, ResponderContext};
-pub(crate) use self::cookie::{Cookie};
+pub(crate) use self::cookie::{Cookie, CookiePair};
+pub(crate) use self::csn::{CsnMode};
+use self::csn::{CsnRejection};
 use self::messages::{
     Message, ServerHello, ServerAuth, ClientHello, ClientAuth,
     NewInitiator, NewResponder, DropResponder, DropReason, Disconnected,
     SendError, Token, Key, Auth, InitiatorAuthBuilder, ResponderAuthBuilder, Close,
 };
+pub(crate) use self::messages::{UnknownFields};
 pub(crate) use self::nonce::{Nonce};
-pub use self::types::Role;
+use self::send_error::{SentMessageIds};
+pub use self::types::{Role, ClientIdentity, SessionSummary, ResetOptions, DroppedMessage};
 pub(crate) use self::types::{HandleAction};
-use self::types::{Identity, ClientIdentity, Address};
-use self::state::{
-    SignalingState, ServerHandshakeState,
-    InitiatorHandshakeState, ResponderHandshakeState,
-};
+use self::types::{Identity, Address};
+pub use self::state::{SignalingState, ServerHandshakeState};
+pub(crate) use self::state::HandshakePhase;
+use self::state::{InitiatorHandshakeState, ResponderHandshakeState};
+
+
+/// The maximum number of responders an initiator can keep track of
+/// (the full 0x02..0xff address range).
+pub(crate) const MAX_RESPONDERS: usize = 252;
+
+/// The default number of consecutive messages from a single source that may
+/// be dropped before the connection is closed.
+pub(crate) const MAX_DROPPED_MESSAGES: usize = 16;
+
+/// The maximum number of dropped messages that are kept until they are
+/// drained. Older entries are discarded.
+const MAX_DROPPED_MESSAGE_LOG: usize = 64;
 
 
 /// The main signaling trait.
@@ -88,11 +108,77 @@ pub(crate) trait Signaling {
         }
     }
 
+    /// Return whether the auth token has been consumed, i.e. whether it is
+    /// safe to discard the out-of-band auth token.
+    ///
+    /// For the initiator, this is the case once the token message of a
+    /// responder has been decrypted. For the responder, this is the case
+    /// once the initiator has replied to the token message with its key
+    /// message.
+    fn auth_token_consumed(&self) -> bool {
+        self.common().auth_token_consumed
+    }
+
+    /// Return the public permanent key of the server (if known).
+    ///
+    /// The server never sends its permanent key. This is the key configured
+    /// via [`with_server_key`](../struct.SaltyClientBuilder.html#method.with_server_key).
+    /// Once the server handshake is done, the server has proven ownership of
+    /// that key through the `signed_keys` field of the server-auth message.
+    fn server_permanent_key(&self) -> Option<PublicKey> {
+        self.server().permanent_key().cloned()
+    }
+
+    /// Return the public session key of the server (if known).
+    ///
+    /// This is the key received in the server-hello message.
+    fn server_session_key(&self) -> Option<PublicKey> {
+        self.server().session_key().cloned()
+    }
+
     /// Return the server handshake state.
     fn server_handshake_state(&self) -> ServerHandshakeState {
         self.server().handshake_state()
     }
 
+    /// Return whether the server handshake is done.
+    fn is_server_handshake_done(&self) -> bool {
+        self.server_handshake_state() == ServerHandshakeState::Done
+    }
+
+    /// Return whether both the server and the peer handshake are done.
+    fn is_handshake_done(&self) -> bool {
+        self.common().signaling_state() == SignalingState::Task
+    }
+
+    /// Return the name of the chosen task.
+    ///
+    /// This returns `None` until the task negotiation is complete.
+    fn chosen_task(&self) -> Option<&str> {
+        self.common().task_name.as_ref().map(String::as_str)
+    }
+
+    /// Return the number of peers known to this client.
+    ///
+    /// For the initiator, this includes all responders (including the
+    /// chosen one). For the responder, this is 1 if an initiator is
+    /// connected.
+    fn peer_count(&self) -> usize;
+
+    /// Return a summary of the current signaling session.
+    fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            role: self.role(),
+            identity: self.identity(),
+            signaling_state: self.common().signaling_state(),
+            server_handshake_state: self.server_handshake_state(),
+            responder_count: match self.role() {
+                Role::Initiator => self.peer_count(),
+                Role::Responder => 0,
+            },
+        }
+    }
+
     /// Validate the nonce.
     fn validate_nonce(&mut self, nonce: &Nonce) -> Result<(), ValidationError> {
         self.validate_nonce_destination(nonce)?;
@@ -121,6 +207,9 @@ pub(crate) trait Signaling {
     /// May return `None` if the peer is not yet set.
     fn get_peer(&self) -> Option<&PeerContext>;
 
+    /// Return the peer context with the specified address.
+    fn get_peer_with_address(&self, addr: Address) -> Option<&PeerContext>;
+
     /// Return the peer context with the specified address.
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut PeerContext>;
 
@@ -150,7 +239,11 @@ pub(crate) trait Signaling {
         //
         // * MUST check that the combined sequence number of the source peer
         //   has been increased by 1 and has not reset to 0.
+        //
+        // Note: If a CSN window has been configured, reordered CSNs within
+        // that window are accepted as well (but never more than once).
         let role = self.role();
+        let csn_mode = self.common().csn_mode;
         let peer: &mut PeerContext = self.get_peer_with_address_mut(nonce.source()).ok_or_else(|| {
             if role == Role::Initiator && nonce.source().is_responder() {
                 ValidationError::Fail(format!("Could not find responder with address {}", nonce.source()))
@@ -162,34 +255,22 @@ pub(crate) trait Signaling {
         let peer_identity = peer.identity();
         let mut csn_pair = peer.csn_pair().borrow_mut();
 
-        // If we already have the CSN of the peer,
-        // ensure that it has been increased properly.
-        if let Some(ref mut csn) = csn_pair.theirs {
-            let previous = csn;
-            let current = nonce.csn();
-            if current < previous {
-                let msg = format!("The {} CSN is lower than last time", peer_identity);
-                return Err(ValidationError::Fail(msg));
-            } else if current == previous {
-                let msg = format!("The {} CSN hasn't been incremented", peer_identity);
-                return Err(ValidationError::Fail(msg));
-            } else {
-                *previous = current.clone();
-            }
+        // If this is the first message from that peer,
+        // validate the overflow number.
+        if csn_pair.theirs.is_none() && nonce.csn().overflow_number() != 0 {
+            let msg = format!("First message from {} must have set the overflow number to 0", peer_identity);
+            return Err(ValidationError::Fail(msg));
         }
 
-        // Otherwise, this is the first message from that peer.
-        if csn_pair.theirs.is_none() {
-            // Validate the overflow number...
-            if nonce.csn().overflow_number() != 0 {
-                let msg = format!("First message from {} must have set the overflow number to 0", peer.identity());
-                return Err(ValidationError::Fail(msg));
-            }
-            // ...and store the CSN.
-            csn_pair.theirs = Some(nonce.csn().clone());
-        }
-
-        Ok(())
+        // Ensure that the CSN has been increased properly and store it.
+        csn_pair.update_theirs(nonce.csn(), csn_mode).map_err(|rejection| {
+            let msg = match rejection {
+                CsnRejection::Lower => format!("The {} CSN is lower than last time", peer_identity),
+                CsnRejection::NotIncremented => format!("The {} CSN hasn't been incremented", peer_identity),
+                CsnRejection::Replayed => format!("The {} CSN has already been used", peer_identity),
+            };
+            ValidationError::Fail(msg)
+        })
     }
 
     /// Validate the nonce cookie.
@@ -207,7 +288,15 @@ pub(crate) trait Signaling {
         // Otherwise, the peer:
         //
         // * MUST ensure that the 16 byte cookie of the sender has not changed
+        //
+        // Additionally, as a defensive measure, a peer's cookie must differ
+        // from the server's cookie.
         let role = self.role();
+        let server_cookie = if nonce.source().is_server() {
+            None
+        } else {
+            self.server().cookie_pair().theirs.clone()
+        };
         let peer: &mut PeerContext = self.get_peer_with_address_mut(nonce.source()).ok_or_else(|| {
             if role == Role::Initiator && nonce.source().is_responder() {
                 ValidationError::Fail(format!("Could not find responder with address {}", nonce.source()))
@@ -227,7 +316,12 @@ pub(crate) trait Signaling {
                     Err(ValidationError::Fail(
                         format!("Cookie from {} is identical to our own cookie", peer_identity)
                     ))
-                } else  {
+                } else if server_cookie.as_ref() == Some(nonce.cookie()) {
+                    // ...ensure that it does not mirror the server cookie...
+                    Err(ValidationError::Fail(
+                        format!("Cookie from {} is identical to the server cookie", peer_identity)
+                    ))
+                } else {
                     // ...and store it.
                     cookie_pair.theirs = Some(nonce.cookie().clone());
                     Ok(())
@@ -236,9 +330,7 @@ pub(crate) trait Signaling {
             Some(ref cookie) => {
                 // Ensure that the cookie has not changed
                 if nonce.cookie() != cookie {
-                    Err(ValidationError::Fail(
-                        format!("Cookie from {} has changed", peer_identity)
-                    ))
+                    Err(ValidationError::CookieChanged(peer_identity))
                 } else {
                     Ok(())
                 }
@@ -253,11 +345,34 @@ pub(crate) trait Signaling {
         // Validate the nonce
         match self.validate_nonce(&bbox.nonce) {
             // It's valid! Carry on.
-            Ok(_) => {},
+            Ok(_) => {
+                self.common_mut().dropped_message_counts.remove(&bbox.nonce.source());
+            },
 
             // Drop and ignore some of the messages
+            // Once too many consecutive messages from the same source have
+            // been dropped, close the connection.
             Err(ValidationError::DropMsg(warning)) => {
                 warn!("Invalid nonce: {}", warning);
+                let source = bbox.nonce.source();
+                {
+                    let common = self.common_mut();
+                    if common.dropped_messages.len() >= MAX_DROPPED_MESSAGE_LOG {
+                        common.dropped_messages.remove(0);
+                    }
+                    let timestamp = common.clock.now();
+                    common.dropped_messages.push(DroppedMessage { source: source.0, reason: warning, timestamp });
+                } // Waiting for NLL
+                let max = self.common().max_dropped_messages;
+                let count = {
+                    let count = self.common_mut().dropped_message_counts.entry(source).or_insert(0);
+                    *count += 1;
+                    *count
+                };
+                if count > max {
+                    warn!("Dropped {} consecutive messages from {}, disconnecting", count, source);
+                    return Ok(vec![HandleAction::Disconnect(CloseCode::ProtocolError)]);
+                }
                 return Ok(vec![]);
             },
 
@@ -265,6 +380,10 @@ pub(crate) trait Signaling {
             Err(ValidationError::Fail(reason)) =>
                 return Err(SignalingError::InvalidNonce(reason)),
 
+            // The cookie of the peer has changed, fail the signaling
+            Err(ValidationError::CookieChanged(identity)) =>
+                return Err(SignalingError::PeerCookieChanged(identity)),
+
             // A critical error occurred
             Err(ValidationError::Crash(reason)) =>
                 return Err(SignalingError::Crash(reason)),
@@ -298,6 +417,128 @@ pub(crate) trait Signaling {
         }
     }
 
+    /// Reset the signaling instance, so that it can be used for a new
+    /// connection to the server.
+    ///
+    /// The cookies used towards the server and the peer are regenerated or
+    /// reused depending on the specified `options`. All other server and peer
+    /// state is discarded. An auth token that has already been sent to the
+    /// initiator is not restored.
+    ///
+    /// This is not possible once the peer handshake has finished.
+    fn reset_with_options(&mut self, options: ResetOptions) -> SignalingResult<()> {
+        if self.common().signaling_state() == SignalingState::Task {
+            return Err(SignalingError::Protocol(
+                "Cannot reset the signaling after the peer handshake has finished".into()
+            ));
+        }
+        debug!("Resetting signaling ({:?})", options);
+
+        let old_server = mem::replace(&mut self.common_mut().server, ServerContext::new());
+        {
+            let server = &mut self.common_mut().server;
+            server.permanent_key = old_server.permanent_key;
+            if options.reuse_server_cookie {
+                server.cookie_pair.ours = old_server.cookie_pair.ours;
+                server.csn_pair.borrow_mut().ours = old_server.csn_pair.into_inner().ours;
+            } else {
+                server.cookie_pair = CookiePair::new_distinct_from(&old_server.cookie_pair.ours);
+            }
+        } // Waiting for NLL
+
+        {
+            let common = self.common_mut();
+            common.signaling_state = SignalingState::ServerHandshake;
+            common.identity = ClientIdentity::Unknown;
+            common.sent_message_ids = RefCell::new(SentMessageIds::new());
+            common.dropped_message_counts.clear();
+        } // Waiting for NLL
+
+        self.reset_peers(options.reuse_peer_cookie);
+        Ok(())
+    }
+
+    /// Discard the peer state as part of a reset.
+    ///
+    /// If `reuse_cookie` is set, our cookie and CSN towards the peer are kept.
+    fn reset_peers(&mut self, reuse_cookie: bool);
+
+    /// Replace our permanent keypair, e.g. to migrate to a new identity.
+    ///
+    /// This is only possible before the server handshake has started, for
+    /// example right after a [`reset`](#method.reset_with_options).
+    fn set_permanent_key(&mut self, keypair: KeyPair) -> SignalingResult<()> {
+        if self.common().signaling_state() != SignalingState::ServerHandshake
+                || self.server_handshake_state() != ServerHandshakeState::New {
+            return Err(SignalingError::Protocol(
+                "Cannot replace the permanent key after the server handshake has started".into()
+            ));
+        }
+        debug!("Replacing permanent key");
+        self.common_mut().permanent_keypair = keypair;
+        Ok(())
+    }
+
+    /// Return the types of the messages that would currently be accepted.
+    ///
+    /// This is only meant for diagnostics. Messages exchanged through a task
+    /// are not included.
+    fn expected_message_types(&self) -> Vec<&'static str> {
+        let mut types = match self.server_handshake_state() {
+            ServerHandshakeState::New => vec!["server-hello"],
+            ServerHandshakeState::ClientInfoSent => vec!["server-auth"],
+            ServerHandshakeState::Done => match self.role() {
+                Role::Initiator => vec!["new-responder", "send-error", "disconnected"],
+                Role::Responder => vec!["new-initiator", "send-error", "disconnected"],
+            },
+        };
+        if self.common().signaling_state() == SignalingState::PeerHandshake {
+            types.extend(self.expected_peer_message_types());
+        }
+        types
+    }
+
+    /// Return the types of the peer handshake messages that would currently
+    /// be accepted.
+    fn expected_peer_message_types(&self) -> Vec<&'static str>;
+
+    /// Drain the diagnostics for all messages that were dropped since the
+    /// last call, oldest first.
+    ///
+    /// At most `MAX_DROPPED_MESSAGE_LOG` entries are kept.
+    fn drain_dropped_messages(&mut self) -> Vec<DroppedMessage> {
+        mem::replace(&mut self.common_mut().dropped_messages, vec![])
+    }
+
+    /// Handle a stalled handshake.
+    ///
+    /// Timing is up to the network layer. Once it detects that the
+    /// specified handshake `phase` has stalled, it should call this method
+    /// and process the returned actions. If the handshake has already moved
+    /// past that phase, the timeout is ignored.
+    fn on_timeout(&mut self, phase: HandshakePhase) -> Vec<HandleAction> {
+        let current_phase = match self.common().signaling_state() {
+            SignalingState::ServerHandshake => Some(HandshakePhase::Server),
+            SignalingState::PeerHandshake => Some(HandshakePhase::Peer),
+            SignalingState::Task => None,
+        };
+        if current_phase != Some(phase) {
+            debug!("Ignoring {:?} handshake timeout in {:?} state", phase, self.common().signaling_state());
+            return vec![];
+        }
+        warn!("{:?} handshake timed out, disconnecting", phase);
+        vec![HandleAction::Disconnect(CloseCode::Timeout)]
+    }
+
+    /// Handle a raw incoming frame (nonce + payload).
+    ///
+    /// This decodes the frame into a [`ByteBox`](../boxes/struct.ByteBox.html)
+    /// and passes it on to [`handle_message`](#method.handle_message).
+    fn handle_frame(&mut self, frame: &[u8]) -> SignalingResult<Vec<HandleAction>> {
+        let bbox = ByteBox::from_slice(frame)?;
+        self.handle_message(bbox)
+    }
+
     /// Handle an incoming handshake message from a peer.
     fn handle_handshake_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
         trace!("handle_handshake_peer_message");
@@ -320,9 +561,15 @@ pub(crate) trait Signaling {
                         DropReason::InitiatorCouldNotDecrypt,
                     )?;
                     debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
-                    return Ok(vec![drop_responder]);
+                    return Ok(vec![
+                        drop_responder,
+                        HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(source_address.0))),
+                    ]);
+                },
+                Err(e) => {
+                    self.handle_peer_handshake_failure();
+                    return Err(e);
                 },
-                Err(e) => return Err(e),
             }
         };
 
@@ -344,6 +591,11 @@ pub(crate) trait Signaling {
         }
     }
 
+    /// Discard the session keys of a peer handshake that failed.
+    ///
+    /// The default implementation does nothing.
+    fn handle_peer_handshake_failure(&mut self) {}
+
     /// Handle an incoming task message from a peer.
     fn handle_task_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
         trace!("handle_task_peer_message");
@@ -422,15 +674,39 @@ pub(crate) trait Signaling {
 
     /// Decode or decrypt a binary message coming from the server.
     fn decode_server_message(&self, bbox: ByteBox) -> SignalingResult<OpenBox<Message>> {
+        let server_handshake_state = self.server_handshake_state();
+
+        // Keep the payload around, so that we can report it if decoding
+        // fails because the server picked the wrong encoding.
+        let ByteBox { bytes, nonce } = bbox;
+
         // The very first message from the server is unencrypted
         if self.common().signaling_state() == SignalingState::ServerHandshake
-        && self.server_handshake_state() == ServerHandshakeState::New {
-            return OpenBox::decode(bbox);
+        && server_handshake_state == ServerHandshakeState::New {
+            return OpenBox::decode_bytes(&bytes, nonce, self.common().unknown_fields)
+                .map_err(|e| match Message::peek_type(&bytes) {
+                    Some(_) => e,
+                    None => SignalingError::Protocol(format!(
+                        "Expected unencrypted message from server in state {:?}", server_handshake_state
+                    )),
+                });
         }
 
         // Otherwise, decrypt with server key
         match self.server().session_key {
-            Some(ref pubkey) => OpenBox::<Message>::decrypt(bbox, &self.common().permanent_keypair, pubkey),
+            Some(ref pubkey) => OpenBox::<Message>::decrypt_bytes(
+                &bytes,
+                nonce,
+                &self.common().permanent_keypair,
+                pubkey,
+                self.common().unknown_fields,
+            ).map_err(|e| match e {
+                SignalingError::DecryptionFailed { .. } if Message::peek_type(&bytes).is_some() =>
+                    SignalingError::Protocol(format!(
+                        "Expected encrypted message from server in state {:?}", server_handshake_state
+                    )),
+                other => other,
+            }),
             None => Err(SignalingError::Crash("Missing server session key".into())),
         }
     }
@@ -469,16 +745,19 @@ pub(crate) trait Signaling {
             .ok_or_else(|| SignalingError::Crash("Peer not set".into()))?;
 
         // Create and encrypt message
-        let nonce = Nonce::new(
+        let nonce = Nonce::new_checked(
             // Cookie
             peer.cookie_pair().ours.clone(),
             // Src
-            self.common().identity.into(),
+            Address::try_from_client_identity(self.common().identity)?,
             // Dst
             peer.identity().into(),
             // Csn
             peer.csn_pair().borrow_mut().ours.increment()?,
-        );
+            // Role
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&nonce);
         let obox = OpenBox::<Value>::new(value, nonce);
         let bbox = obox.encrypt(
             peer.keypair().ok_or_else(|| SignalingError::Crash("Session keypair not available".into()))?,
@@ -488,6 +767,35 @@ pub(crate) trait Signaling {
         Ok(bbox)
     }
 
+    /// Encrypt a message for the specified peer using the session keys.
+    ///
+    /// A new nonce is created for every message, incrementing our CSN for
+    /// that peer.
+    fn encrypt_for_peer(&self, peer: Identity, message: Message) -> SignalingResult<ByteBox> {
+        if peer == Identity::Server {
+            return Err(SignalingError::Crash("Cannot encrypt a peer message for the server".into()));
+        }
+        let peer = self.get_peer_with_address(peer.into())
+            .ok_or_else(|| SignalingError::Crash(format!("Unknown peer: {}", peer)))?;
+
+        // Create and encrypt message
+        let nonce = Nonce::new_checked(
+            peer.cookie_pair().ours.clone(),
+            Address::try_from_client_identity(self.common().identity)?,
+            peer.identity().into(),
+            peer.csn_pair().borrow_mut().ours.increment()?,
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&nonce);
+        let obox = OpenBox::<Message>::new(message, nonce);
+        let bbox = obox.encrypt(
+            peer.keypair().ok_or_else(|| SignalingError::Crash("Session keypair not available".into()))?,
+            peer.session_key().ok_or_else(|| SignalingError::Crash("Peer session key not set".into()))?,
+        );
+
+        Ok(bbox)
+    }
+
     /// Encode and encrypt a close message for the chosen peer.
     ///
     /// The `peer_ctx` parameter must only be provided during handshake.
@@ -514,16 +822,19 @@ pub(crate) trait Signaling {
         };
 
         // Create and encrypt message
-        let nonce = Nonce::new(
+        let nonce = Nonce::new_checked(
             // Cookie
             peer.cookie_pair().ours.clone(),
             // Src
-            self.common().identity.into(),
+            Address::try_from_client_identity(self.common().identity)?,
             // Dst
             peer.identity().into(),
             // Csn
             peer.csn_pair().borrow_mut().ours.increment()?,
-        );
+            // Role
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&nonce);
         let msg = Close::from_close_code(reason).into_message();
         let obox = OpenBox::<Message>::new(msg, nonce);
         let bbox = obox.encrypt(
@@ -558,13 +869,32 @@ pub(crate) trait Signaling {
                 self.handle_new_initiator(msg),
             (ServerHandshakeState::Done, Message::NewResponder(msg)) =>
                 self.handle_new_responder(msg),
-            (ServerHandshakeState::Done, Message::DropResponder(_msg)) =>
-                unimplemented!("TODO (#36): Handling DropResponder messages not yet implemented"),
             (ServerHandshakeState::Done, Message::SendError(msg)) =>
                 self.handle_send_error(msg),
             (ServerHandshakeState::Done, Message::Disconnected(msg)) =>
                 self.handle_disconnected(msg),
 
+            // A second server-auth message is a protocol violation
+            (ServerHandshakeState::Done, Message::ServerAuth(_)) => {
+                warn!("Got a second 'server-auth' message from server, disconnecting");
+                Ok(vec![HandleAction::Disconnect(CloseCode::ProtocolError)])
+            },
+
+            // A second server-hello message is a protocol violation
+            (s, Message::ServerHello(_)) => Err(SignalingError::Protocol(
+                format!("Got a second 'server-hello' message from server in {:?} state", s)
+            )),
+
+            // A server-auth message must never precede the server-hello message
+            (ServerHandshakeState::New, Message::ServerAuth(_)) => Err(SignalingError::InvalidStateTransition(
+                "Got 'server-auth' message from server before 'server-hello' message".into()
+            )),
+
+            // Messages that are never sent by the server are invalid
+            (_, ref message) if !message.is_server_to_client() => Err(SignalingError::InvalidMessage(
+                format!("Got '{}' message from server, but it is not a server-to-client message", message.get_type())
+            )),
+
             // Any undefined state transition results in an error
             (s, message) => Err(SignalingError::InvalidStateTransition(
                 format!("Got '{}' message from server in {:?} state", message.get_type(), s)
@@ -583,6 +913,9 @@ pub(crate) trait Signaling {
     // Message handling: Handling
 
     /// Handle an incoming [`ServerHello`](messages/struct.ServerHello.html) message.
+    ///
+    /// Responders get two reply actions: The `client-hello` message followed
+    /// by the `client-auth` message. The actions must be sent in that order.
     fn handle_server_hello(&mut self, msg: ServerHello) -> SignalingResult<Vec<HandleAction>> {
         debug!("--> Received server-hello from server");
 
@@ -591,9 +924,10 @@ pub(crate) trait Signaling {
         // Set the server public session key
         trace!("Server session key is {:?}", msg.key);
         if self.server().session_key.is_some() {
-            return Err(SignalingError::Protocol(
-                "Got a server-hello message, but server session key is already set".to_string()
-            ));
+            return Err(SignalingError::Protocol(format!(
+                "Got a server-hello message in {:?} state, but server session key is already set",
+                self.server_handshake_state()
+            )));
         }
         self.common_mut().server.session_key = Some(msg.key);
 
@@ -607,7 +941,7 @@ pub(crate) trait Signaling {
                 // Cookie
                 self.server().cookie_pair().ours.clone(),
                 // Src
-                self.common().identity.into(),
+                Address::try_from_client_identity(self.common().identity)?,
                 // Dst
                 self.server().identity().into(),
                 // Csn
@@ -635,24 +969,21 @@ pub(crate) trait Signaling {
         };
         let client_auth = ClientAuth {
             your_cookie: self.server().cookie_pair().theirs.clone().unwrap(),
-            subprotocols: vec![::SUBPROTOCOL.into()],
+            subprotocols: vec![self.common().subprotocol.clone()],
             ping_interval,
             your_key: self.server().permanent_key().cloned(),
         }.into_message();
         let client_auth_nonce = Nonce::new(
             self.server().cookie_pair().ours.clone(),
-            self.identity().into(),
+            Address::try_from_client_identity(self.identity())?,
             self.server().identity().into(),
             self.server().csn_pair().borrow_mut().ours.increment()?,
         );
         let reply = OpenBox::<Message>::new(client_auth, client_auth_nonce);
-        match self.server().session_key {
-            Some(ref pubkey) => {
-                debug!("<-- Enqueuing client-auth to server");
-                actions.push(HandleAction::Reply(reply.encrypt(&self.common().permanent_keypair, pubkey)));
-            },
-            None => return Err(SignalingError::Crash("Missing server permanent key".into())),
-        };
+        // The client-hello message (if any) must precede the client-auth message
+        debug_assert_eq!(actions.len(), if self.role() == Role::Responder { 1 } else { 0 });
+        debug!("<-- Enqueuing client-auth to server");
+        actions.push(HandleAction::Reply(self.encrypt_for_server(reply)?));
 
         // TODO (#13): Can we prevent confusing an incoming and an outgoing nonce?
         self.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
@@ -703,10 +1034,10 @@ pub(crate) trait Signaling {
             // key (in that order).
             let server_public_session_key = self.server().session_key()
                 .ok_or_else(|| SignalingError::Crash("Server session key not set".into()))?;
-            if &decrypted.server_public_session_key != server_public_session_key {
+            if !verify_equal(&decrypted.server_public_session_key.0, &server_public_session_key.0) {
                 return Err(SignalingError::Protocol("Server public session key sent in `signed_keys` is not valid".into()));
             }
-            if &decrypted.client_public_permanent_key != self.common().permanent_keypair.public_key() {
+            if !verify_equal(&decrypted.client_public_permanent_key.0, &self.common().permanent_keypair.public_key().0) {
                 return Err(SignalingError::Protocol("Our public permanent key sent in `signed_keys` is not valid".into()));
             }
         } else if msg.signed_keys.is_some() {
@@ -734,13 +1065,24 @@ pub(crate) trait Signaling {
     /// Handle an incoming [`NewResponder`](messages/struct.NewResponder.html) message.
     fn handle_new_responder(&mut self, msg: NewResponder) -> SignalingResult<Vec<HandleAction>>;
 
-    /// Handle an incoming [`SendError`](messages/struct.ServerAuth.html) message.
+    /// Handle an incoming [`SendError`](messages/struct.SendError.html) message.
     fn handle_send_error(&mut self, msg: SendError) -> SignalingResult<Vec<HandleAction>> {
         warn!("--> Received send-error from server");
         debug!("Message that could not be relayed: {:#?}", msg.id);
-        Err(SignalingError::SendError)
+
+        // Make sure that the message was actually sent by us
+        if !self.common().sent_message_ids.borrow_mut().take(&msg.id) {
+            warn!("Ignoring send-error for unknown message");
+            return Ok(vec![]);
+        }
+
+        self.handle_send_error_for_peer(msg.id.destination)
     }
 
+    /// Handle a message to the specified peer that could not be relayed
+    /// by the server.
+    fn handle_send_error_for_peer(&mut self, address: Address) -> SignalingResult<Vec<HandleAction>>;
+
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
     fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<Vec<HandleAction>>;
 
@@ -763,20 +1105,26 @@ pub(crate) trait Signaling {
         let drop = DropResponder::with_reason(addr, reason).into_message();
         let drop_nonce = Nonce::new(
             self.server().cookie_pair.ours.clone(),
-            self.common().identity.into(),
+            Address::try_from_client_identity(self.common().identity)?,
             self.server().identity().into(),
             self.server().csn_pair().borrow_mut().ours.increment()?,
         );
 
         // Encrypt message
         let obox = OpenBox::<Message>::new(drop, drop_nonce);
-        let bbox = obox.encrypt(
-            &self.common().permanent_keypair,
-            self.server().session_key()
-                .ok_or_else(|| SignalingError::Crash("Server session key not set".into()))?
-        );
+        Ok(HandleAction::Reply(self.encrypt_for_server(obox)?))
+    }
 
-        Ok(HandleAction::Reply(bbox))
+    /// Encrypt a message for the server.
+    ///
+    /// All encrypted client-to-server messages, starting with client-auth,
+    /// are encrypted with our permanent key and the server's session key
+    /// from the server-hello message. The server's permanent key is only
+    /// used to verify the `signed_keys` field of the server-auth message.
+    fn encrypt_for_server(&self, obox: OpenBox<Message>) -> SignalingResult<ByteBox> {
+        let session_key = self.server().session_key()
+            .ok_or_else(|| SignalingError::Crash("Server session key not set".into()))?;
+        Ok(obox.encrypt(&self.common().permanent_keypair, session_key))
     }
 }
 
@@ -801,6 +1149,9 @@ pub(crate) struct Common {
     /// key (for trusted sessions).
     pub(crate) auth_provider: Option<AuthProvider>,
 
+    /// Whether the auth token has been consumed by the peer.
+    pub(crate) auth_token_consumed: bool,
+
     /// The assigned role.
     pub(crate) role: Role,
 
@@ -818,6 +1169,11 @@ pub(crate) struct Common {
     /// Be careful when locking the mutex, it's easy to end up with deadlocks!
     pub(crate) task: Option<Arc<Mutex<BoxedTask>>>,
 
+    /// The name of the chosen task.
+    ///
+    /// This will be set once a task is chosen.
+    pub(crate) task_name: Option<String>,
+
     /// The list of message types that the task accepts.
     ///
     /// This will be set once a task is chosen.
@@ -825,6 +1181,32 @@ pub(crate) struct Common {
 
     /// The interval at which the server should send WebSocket ping messages.
     pub(crate) ping_interval: Option<Duration>,
+
+    /// The mode used to validate incoming CSNs.
+    pub(crate) csn_mode: CsnMode,
+
+    /// Whether unknown fields in incoming messages are ignored or rejected.
+    pub(crate) unknown_fields: UnknownFields,
+
+    /// The WebSocket subprotocol announced in the 'client-auth' message.
+    pub(crate) subprotocol: String,
+
+    /// The ids of recently sent peer messages, used to attribute
+    /// 'send-error' messages.
+    pub(crate) sent_message_ids: RefCell<SentMessageIds>,
+
+    /// The number of consecutive messages that may be dropped per source
+    /// before the connection is closed.
+    pub(crate) max_dropped_messages: usize,
+
+    /// The number of consecutively dropped messages per source address.
+    pub(crate) dropped_message_counts: HashMap<Address, usize>,
+
+    /// The dropped messages that haven't been drained yet.
+    pub(crate) dropped_messages: Vec<DroppedMessage>,
+
+    /// The time source used for diagnostics.
+    pub(crate) clock: Box<Clock>,
 }
 
 impl Common {
@@ -892,6 +1274,32 @@ pub(crate) struct InitiatorSignaling {
     // The responder counter, used to give every responder
     // an incrementing serial.
     pub(crate) responder_counter: ResponderCounter,
+
+    // The maximum number of responders to keep track of. If exceeded,
+    // the oldest inactive responders are dropped.
+    pub(crate) max_responders: usize,
+
+    // If set, only responders with one of these permanent keys are accepted
+    pub(crate) allowed_responder_keys: Option<HashSet<PublicKey>>,
+}
+
+/// Show the session state and the addresses of the known responders.
+///
+/// Our keypair, the auth token and the responder keys are omitted.
+impl fmt::Debug for InitiatorSignaling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut responders: Vec<Address> = self.responders.keys().cloned().collect();
+        responders.sort();
+        f.debug_struct("InitiatorSignaling")
+            .field("role", &self.role())
+            .field("identity", &self.identity())
+            .field("signaling_state", &self.common().signaling_state())
+            .field("server_handshake_state", &self.server_handshake_state())
+            .field("responders", &responders)
+            .field("responder", &self.responder.as_ref().map(|r| r.address))
+            .field("task", &self.chosen_task())
+            .finish()
+    }
 }
 
 impl Signaling for InitiatorSignaling {
@@ -909,6 +1317,28 @@ impl Signaling for InitiatorSignaling {
         self.responder.as_ref().map(|p| p as &PeerContext)
     }
 
+    fn peer_count(&self) -> usize {
+        self.responders.len() + if self.responder.is_some() { 1 } else { 0 }
+    }
+
+    fn reset_peers(&mut self, _reuse_cookie: bool) {
+        // Responders will be assigned new addresses by the server
+        self.responders.clear();
+        self.responder = None;
+    }
+
+    fn get_peer_with_address(&self, addr: Address) -> Option<&PeerContext> {
+        let identity: Identity = addr.into();
+        match identity {
+            Identity::Server => Some(&self.common.server as &PeerContext),
+            Identity::Initiator => None,
+            Identity::Responder(_) => match self.responder {
+                Some(ref responder) if responder.address == addr => Some(responder as &PeerContext),
+                _ => self.responders.get(&addr).map(|r| r as &PeerContext),
+            },
+        }
+    }
+
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut PeerContext> {
         let identity: Identity = addr.into();
         match identity {
@@ -968,9 +1398,7 @@ impl Signaling for InitiatorSignaling {
             };
         }
         if nonce.destination() != self.identity().into() {
-            return Err(ValidationError::Fail(
-                format!("Bad destination: {} (our identity is {})", nonce.destination(), self.identity())
-            ));
+            return Err(bad_destination(nonce, self.identity()));
         }
 
         Ok(())
@@ -1001,8 +1429,12 @@ impl Signaling for InitiatorSignaling {
                 }
             },
 
-            // Required due to https://github.com/rust-lang/rfcs/issues/1550
-            Address(_) => unreachable!(),
+            // Required due to https://github.com/rust-lang/rfcs/issues/1550.
+            // Never panic on input from the network, even if this arm should
+            // become reachable in the future.
+            Address(_) => Err(ValidationError::Fail(
+                format!("Invalid source address: {}", nonce.source())
+            )),
         }
     }
 
@@ -1041,7 +1473,14 @@ impl Signaling for InitiatorSignaling {
                 // Expect token message, encrypted with authentication token.
                 debug!("Expect token message");
                 match self.common.auth_provider {
-                    Some(AuthProvider::Token(ref token)) => OpenBox::decrypt_token(bbox, token),
+                    Some(AuthProvider::Token(ref token)) => OpenBox::decrypt_token(bbox, token, self.common.unknown_fields)
+                        .map_err(|e| match e {
+                            SignalingError::DecryptionFailed { .. } => {
+                                warn!("Could not decrypt token message");
+                                SignalingError::InitiatorCouldNotDecrypt
+                            },
+                            e => e,
+                        }),
                     Some(AuthProvider::TrustedKey(_)) => Err(SignalingError::Crash(
                         "Handshake state is \"New\" even though a trusted key is available".into()
                     )),
@@ -1057,9 +1496,10 @@ impl Signaling for InitiatorSignaling {
                 OpenBox::<Message>::decrypt(
                     bbox,
                     &self.common.permanent_keypair,
-                    responder_permanent_key(&responder)?
+                    responder_permanent_key(&responder)?,
+                    self.common.unknown_fields,
                 ).map_err(|e| match e {
-                    SignalingError::Decode(_) => {
+                    SignalingError::DecryptionFailed { .. } => {
                         warn!("Could not decrypt key message");
                         SignalingError::InitiatorCouldNotDecrypt
                     },
@@ -1069,7 +1509,12 @@ impl Signaling for InitiatorSignaling {
             ResponderHandshakeState::KeySent => {
                 // Expect auth message, encrypted with our public session key
                 // and responder private session key
-                OpenBox::<Message>::decrypt(bbox, &responder.keypair, responder_session_key(&responder)?)
+                OpenBox::<Message>::decrypt(
+                    bbox,
+                    &responder.keypair,
+                    responder_session_key(&responder)?,
+                    self.common.unknown_fields,
+                )
             },
             other => {
                 // TODO (#14): Maybe remove these states?
@@ -1095,18 +1540,39 @@ impl Signaling for InitiatorSignaling {
 
         // State transitions
         match (old_state, obox.message) {
+            (_, ref message) if !message.is_client_to_client() =>
+                Err(not_client_to_client(message.get_type(), source.into())),
+
             // Valid state transitions
             (ResponderHandshakeState::New, Message::Token(msg)) => self.handle_token(msg, source),
             (ResponderHandshakeState::TokenReceived, Message::Key(msg)) => self.handle_key(msg, source),
             (ResponderHandshakeState::KeySent, Message::Auth(msg)) => self.handle_auth(msg, source),
 
-            // Any undefined state transition results in an error
-            (s, message) => Err(SignalingError::InvalidStateTransition(
-                format!("Got {} message from responder {} in {:?} state", message.get_type(), obox.nonce.source().0, s)
+            // A responder must send a token before its key
+            (ResponderHandshakeState::New, Message::Key(_)) => Err(SignalingError::InvalidStateTransition(
+                format!("Got key message from responder {} before token message", source)
             )),
+
+            // Any undefined state transition is an error
+            (state, message) => Err(invalid_transition(message.get_type(), source.into(), state)),
         }
     }
 
+    fn expected_peer_message_types(&self) -> Vec<&'static str> {
+        let any_in_state = |state| self.responders.values().any(|r| r.handshake_state() == state);
+        let mut types = vec![];
+        if any_in_state(ResponderHandshakeState::New) {
+            types.push("token");
+        }
+        if any_in_state(ResponderHandshakeState::TokenReceived) {
+            types.push("key");
+        }
+        if any_in_state(ResponderHandshakeState::KeySent) {
+            types.push("auth");
+        }
+        types
+    }
+
     fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<Vec<HandleAction>> {
         // In case the client is the initiator, it SHALL check that the
         // responders field is set and contains an Array of responder
@@ -1124,13 +1590,14 @@ impl Signaling for InitiatorSignaling {
         };
 
         // The responder identities MUST be validated and SHALL neither contain
-        // addresses outside the range 0x02..0xff
-        let responders_set: HashSet<Address> = responders.iter().cloned().collect();
-        if responders_set.contains(&Address(0x00)) || responders_set.contains(&Address(0x01)) {
-            return Err(SignalingError::InvalidMessage(
-                "`responders` field in server-auth message may not contain addresses <0x02".into()
-            ));
+        // addresses outside the range 0x02..0xff. The only reserved addresses
+        // are 0x00 (server) and 0x01 (initiator, i.e. our own address).
+        if let Some(address) = responders.iter().find(|address| !address.is_responder()) {
+            return Err(SignalingError::InvalidMessage(format!(
+                "`responders` field in server-auth message may not contain addresses <0x02, found {}", address
+            )));
         }
+        let responders_set: HashSet<Address> = responders.iter().cloned().collect();
 
         // ...nor SHALL an address be repeated in the Array.
         if responders.len() != responders_set.len() {
@@ -1146,18 +1613,17 @@ impl Signaling for InitiatorSignaling {
         // It SHOULD store the responder's identities in its internal list of
         // responders. Additionally, the initiator MUST keep its path clean by
         // following the procedure described in the Path Cleaning section.
+        // Responders are registered in the order provided by the server.
         let mut actions = vec![];
-        for address in responders_set {
-            if let Some(drop_responder) = self.process_new_responder(address)? {
-                actions.push(drop_responder);
-            }
+        for address in responders.iter().cloned() {
+            actions.extend(self.process_new_responder(address)?);
         }
 
         actions.push(HandleAction::Event(Event::ServerHandshakeDone(responders.is_empty())));
         Ok(actions)
     }
 
-    /// Handle an incoming [`NewInitiator`](messages/struct.Initiator.html) message.
+    /// Handle an incoming [`NewInitiator`](messages/struct.NewInitiator.html) message.
     fn handle_new_initiator(&mut self, _msg: NewInitiator) -> SignalingResult<Vec<HandleAction>> {
         Err(SignalingError::Protocol("Received 'new-responder' message as initiator".into()))
     }
@@ -1168,17 +1634,28 @@ impl Signaling for InitiatorSignaling {
 
         // An initiator who receives a 'new-responder' message SHALL validate
         // that the id field contains a valid responder address (0x02..0xff).
-        if !msg.id.is_responder() {
-            return Err(SignalingError::InvalidMessage(
-                "`id` field in new-responder message is not a valid responder address".into()
-            ));
+        let peer = match msg.identity() {
+            Some(peer) => peer,
+            None if msg.id.is_server() => return Err(SignalingError::InvalidMessage(
+                "`id` field in new-responder message contains the server address (0x00)".into()
+            )),
+            None => return Err(SignalingError::InvalidMessage(
+                "`id` field in new-responder message contains the initiator address (0x01)".into()
+            )),
+        };
+
+        // Never reset the session with the chosen responder
+        if self.responder.as_ref().map_or(false, |r| r.address == msg.id) {
+            warn!("Received new-responder for the chosen responder {}, ignoring", msg.id);
+            return Ok(vec![]);
         }
 
         // Process responder
-        match self.process_new_responder(msg.id)? {
-            Some(drop_responder) => Ok(vec![drop_responder]),
-            None => Ok(vec![]),
-        }
+        let mut actions = self.process_new_responder(msg.id)?;
+
+        // Notify the application about the new peer
+        actions.push(HandleAction::Event(Event::NewPeer(peer)));
+        Ok(actions)
     }
 
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
@@ -1193,7 +1670,23 @@ impl Signaling for InitiatorSignaling {
             ));
         }
 
-        Ok(vec![HandleAction::Event(Event::Disconnected(msg.id.0))])
+        // If the responder is still in the handshake, drop its context.
+        if self.common().signaling_state() != SignalingState::Task && self.responders.remove(&msg.id).is_some() {
+            debug!("Removed responder context for address {}", msg.id);
+        }
+
+        Ok(vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(msg.id.0)))])
+    }
+
+    fn handle_send_error_for_peer(&mut self, address: Address) -> SignalingResult<Vec<HandleAction>> {
+        // A responder that is still in the handshake is simply dropped.
+        if self.responders.remove(&address).is_some() {
+            debug!("Removed responder context for address {}", address);
+            return Ok(vec![
+                HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(address.0))),
+            ]);
+        }
+        Err(SignalingError::SendError)
     }
 }
 
@@ -1202,7 +1695,8 @@ impl InitiatorSignaling {
                       tasks: Tasks,
                       responder_trusted_pubkey: Option<PublicKey>,
                       server_public_permanent_key: Option<PublicKey>,
-                      ping_interval: Option<Duration>) -> Self {
+                      ping_interval: Option<Duration>,
+                      max_responders: usize) -> Self {
         InitiatorSignaling {
             common: Common {
                 signaling_state: SignalingState::ServerHandshake,
@@ -1213,6 +1707,7 @@ impl InitiatorSignaling {
                     Some(key) => AuthProvider::TrustedKey(key),
                     None => AuthProvider::Token(AuthToken::new()),
                 }),
+                auth_token_consumed: false,
                 server: {
                     let mut ctx = ServerContext::new();
                     ctx.permanent_key = server_public_permanent_key;
@@ -1220,12 +1715,23 @@ impl InitiatorSignaling {
                 },
                 tasks: Some(tasks),
                 task: None,
+                task_name: None,
                 task_supported_types: None,
                 ping_interval,
+                csn_mode: CsnMode::default(),
+                unknown_fields: UnknownFields::default(),
+                subprotocol: ::SUBPROTOCOL.into(),
+                sent_message_ids: RefCell::new(SentMessageIds::new()),
+                max_dropped_messages: MAX_DROPPED_MESSAGES,
+                dropped_message_counts: HashMap::new(),
+                dropped_messages: vec![],
+                clock: Box::new(SystemClock),
             },
             responders: HashMap::new(),
             responder: None,
             responder_counter: ResponderCounter::new(),
+            max_responders,
+            allowed_responder_keys: None,
         }
     }
 
@@ -1234,6 +1740,20 @@ impl InitiatorSignaling {
     fn handle_token(&mut self, msg: Token, source: Address) -> SignalingResult<Vec<HandleAction>> {
         debug!("--> Received token from {}", Identity::from(source));
 
+        // If an allowlist is configured, drop responders with other keys
+        let is_allowed = self.allowed_responder_keys.as_ref()
+            .map_or(true, |keys| keys.contains(&msg.key));
+        if !is_allowed {
+            warn!("Responder {} presented a permanent key that is not allowed, dropping it", source);
+            self.responders.remove(&source);
+            let drop_responder = self.send_drop_responder(source, DropReason::InvalidKey)?;
+            debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
+            return Ok(vec![
+                drop_responder,
+                HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(source.0))),
+            ]);
+        }
+
         {
             // Find responder instance
             let responder = self.responders.get_mut(&source)
@@ -1260,6 +1780,7 @@ impl InitiatorSignaling {
             _ => return Err(SignalingError::Crash("Auth provider is not a token".into())),
         }
         self.common_mut().auth_provider = None;
+        self.common_mut().auth_token_consumed = true;
 
         Ok(vec![])
     }
@@ -1292,6 +1813,11 @@ impl InitiatorSignaling {
             }
         };
 
+        // Ensure that the responder's session key != our session key
+        if &msg.key == responder.keypair.public_key() {
+            return Err(SignalingError::Protocol("Responder session key and our session key are equal".into()));
+        }
+
         // Set public session key
         responder.session_key = Some(msg.key);
 
@@ -1300,12 +1826,14 @@ impl InitiatorSignaling {
 
         // Reply with our own key msg
         let key: Message = Key { key: *responder.keypair.public_key() }.into_message();
-        let key_nonce = Nonce::new(
+        let key_nonce = Nonce::new_checked(
             responder.cookie_pair().ours.clone(),
-            self.common.identity.into(),
+            Address::try_from_client_identity(self.common.identity)?,
             responder.identity().into(),
             responder.csn_pair().borrow_mut().ours.increment()?,
-        );
+            self.common.role,
+        )?;
+        self.common.sent_message_ids.borrow_mut().record(&key_nonce);
         let obox = OpenBox::<Message>::new(key, key_nonce);
         let bbox = obox.encrypt(
             &self.common.permanent_keypair,
@@ -1415,6 +1943,7 @@ impl InitiatorSignaling {
                 let drop_responder = self.send_drop_responder(*addr, DropReason::DroppedByInitiator)?;
                 debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
                 actions.push(drop_responder);
+                actions.push(HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(addr.0))));
             }
 
             // Remove responders
@@ -1428,29 +1957,11 @@ impl InitiatorSignaling {
         responder.set_handshake_state(ResponderHandshakeState::AuthReceived);
 
         // Respond with auth message
-        let responder_cookie = responder.cookie_pair.theirs.as_ref().cloned()
-            .ok_or_else(|| SignalingError::Crash("Responder cookie not set".into()))?;
-        let auth: Message = InitiatorAuthBuilder::new(responder_cookie)
-            .set_task(chosen_task.name(), chosen_task.data())
-            .build()?
-            .into_message();
-        let auth_nonce = Nonce::new(
-            responder.cookie_pair().ours.clone(),
-            self.common.identity.into(),
-            responder.address,
-            responder.csn_pair().borrow_mut().ours.increment()?,
-        );
-        let obox = OpenBox::<Message>::new(auth, auth_nonce);
-        let bbox = obox.encrypt(
-            &responder.keypair,
-            responder.session_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash("Responder session key not set".into()))?,
-        );
-        debug!("<-- Enqueuing auth to {}", &responder.identity());
-        actions.push(HandleAction::Reply(bbox));
+        actions.push(self.send_auth(&responder, &chosen_task)?);
 
         // Store chosen task
         self.common_mut().task_supported_types = Some(chosen_task.supported_types());
+        self.common_mut().task_name = Some(chosen_task.name().into_owned());
         self.common_mut().task = Some(Arc::new(Mutex::new(chosen_task)));
 
         // State transitions
@@ -1463,20 +1974,30 @@ impl InitiatorSignaling {
         Ok(actions)
     }
 
-    fn process_new_responder(&mut self, address: Address) -> SignalingResult<Option<HandleAction>> {
+    fn process_new_responder(&mut self, address: Address) -> SignalingResult<Vec<HandleAction>> {
         // If a responder with the same id already exists,
         // all currently cached information about and for the previous responder
         // (such as cookies and the sequence number) MUST be deleted first.
-        if self.responders.contains_key(&address) {
-            warn!("Overwriting responder context for address {:?}", address);
-            self.responders.remove(&address);
-        } else {
-            info!("Registering new responder with address {:?}", address);
-        }
+        let previous_cookie = match self.responders.remove(&address) {
+            Some(previous) => {
+                warn!("Overwriting responder context for address {:?}", address);
+                previous.cookie_pair.theirs
+            },
+            None => {
+                info!("Registering new responder with address {:?}", address);
+                None
+            },
+        };
 
         // Create responder context
         let mut responder = ResponderContext::new(address, self.responder_counter.increment()?);
 
+        // If we already know a cookie of the previous responder, make sure
+        // not to reuse it as our own cookie.
+        if let Some(cookie) = previous_cookie {
+            responder.cookie_pair = CookiePair::new_distinct_from(&cookie);
+        }
+
         // If we trust the responder…
         if let Some(AuthProvider::TrustedKey(key)) = self.common.auth_provider {
             // …set the public permanent key
@@ -1493,26 +2014,62 @@ impl InitiatorSignaling {
         // list of responders.
         self.responders.insert(address, responder);
 
-        let mut action = None;
+        let mut actions = vec![];
 
         // Furthermore, the initiator MUST keep its path clean by following the
         // procedure described in the Path Cleaning section.
-        // To implement this requirement, if we almost reached the responder limit,
-        // drop the oldest responder that hasn't sent any valid data so far.
-        if self.responders.len() > (254 - 2) {
-            if let Some(drop_action) = self.drop_oldest_inactive_responder()? {
-                debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
-                action = Some(drop_action);
+        // To implement this requirement, if we exceeded the responder limit,
+        // drop the oldest responders that haven't sent any valid data so far.
+        while self.responders.len() > self.max_responders {
+            match self.drop_oldest_inactive_responder()? {
+                Some((dropped, drop_action)) => {
+                    debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
+                    actions.push(drop_action);
+                    actions.push(HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(dropped.0))));
+                },
+                None => break,
             }
         }
 
-        Ok(action)
+        Ok(actions)
+    }
+
+    /// Build an `Auth` message for the specified responder, containing the
+    /// chosen task and its data.
+    fn send_auth(&self, responder: &ResponderContext, task: &BoxedTask) -> SignalingResult<HandleAction> {
+        // The your_cookie field SHALL contain the cookie the responder has
+        // used in its previous messages to us.
+        let responder_cookie = responder.cookie_pair().theirs.as_ref().cloned()
+            .ok_or_else(|| SignalingError::Crash("Responder cookie not set".into()))?;
+        let auth: Message = InitiatorAuthBuilder::new(responder_cookie)
+            .set_task(task.name(), task.data())
+            .build()?
+            .into_message();
+        let auth_nonce = Nonce::new_checked(
+            responder.cookie_pair().ours.clone(),
+            Address::try_from_client_identity(self.common.identity)?,
+            responder.address,
+            responder.csn_pair().borrow_mut().ours.increment()?,
+            self.common.role,
+        )?;
+        self.common.sent_message_ids.borrow_mut().record(&auth_nonce);
+        let obox = OpenBox::<Message>::new(auth, auth_nonce);
+
+        // The message SHALL be NaCl public-key encrypted by the session keys.
+        let bbox = obox.encrypt(
+            &responder.keypair,
+            responder.session_key.as_ref()
+                .ok_or_else(|| SignalingError::Crash("Responder session key not set".into()))?,
+        );
+
+        debug!("<-- Enqueuing auth to {}", responder.identity());
+        Ok(HandleAction::Reply(bbox))
     }
 
     /// Drop the oldest responder that hasn't sent any valid data so far.
-    /// Return a result with a 'drop-responder' handle action if a drop
-    /// candidate has been found.
-    fn drop_oldest_inactive_responder(&mut self) -> SignalingResult<Option<HandleAction>> {
+    /// Return a result with the address of the dropped responder and a
+    /// 'drop-responder' handle action if a drop candidate has been found.
+    fn drop_oldest_inactive_responder(&mut self) -> SignalingResult<Option<(Address, HandleAction)>> {
         debug!("Path almost full, dropping the oldest inactive responder.");
 
         // Find address of drop candidate
@@ -1538,7 +2095,7 @@ impl InitiatorSignaling {
         // Enqueue a drop-responder message
         self
             .send_drop_responder(responder.address, DropReason::DroppedByInitiator)
-            .map(Option::Some)
+            .map(|action| Some((responder.address, action)))
     }
 }
 
@@ -1550,6 +2107,26 @@ pub(crate) struct ResponderSignaling {
 
     // The initiator context
     pub(crate) initiator: InitiatorContext,
+
+    // Whether to fail on unexpected server messages that are harmless
+    // (e.g. 'new-responder') instead of ignoring them
+    pub(crate) strict_protocol: bool,
+}
+
+/// Show the session state and the handshake state towards the initiator.
+///
+/// Our keypair, the auth token and the initiator keys are omitted.
+impl fmt::Debug for ResponderSignaling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponderSignaling")
+            .field("role", &self.role())
+            .field("identity", &self.identity())
+            .field("signaling_state", &self.common().signaling_state())
+            .field("server_handshake_state", &self.server_handshake_state())
+            .field("initiator_handshake_state", &self.initiator.handshake_state())
+            .field("task", &self.chosen_task())
+            .finish()
+    }
 }
 
 impl Signaling for ResponderSignaling {
@@ -1567,6 +2144,32 @@ impl Signaling for ResponderSignaling {
         Some(&self.initiator as &PeerContext)
     }
 
+    fn peer_count(&self) -> usize {
+        if self.initiator.handshake_state() == InitiatorHandshakeState::New { 0 } else { 1 }
+    }
+
+    fn reset_peers(&mut self, reuse_cookie: bool) {
+        let permanent_key = self.initiator.permanent_key;
+        let old_initiator = mem::replace(&mut self.initiator, InitiatorContext::new(permanent_key));
+        if reuse_cookie {
+            // Only our side is kept: The initiator starts over with a new
+            // cookie and CSN, so its next message is its first one again.
+            self.initiator.cookie_pair.ours = old_initiator.cookie_pair.ours;
+            self.initiator.csn_pair.borrow_mut().ours = old_initiator.csn_pair.into_inner().ours;
+        } else {
+            self.initiator.cookie_pair = CookiePair::new_distinct_from(&old_initiator.cookie_pair.ours);
+        }
+    }
+
+    fn get_peer_with_address(&self, addr: Address) -> Option<&PeerContext> {
+        let identity: Identity = addr.into();
+        match identity {
+            Identity::Server => Some(&self.common.server),
+            Identity::Initiator => Some(&self.initiator),
+            Identity::Responder(_) => None,
+        }
+    }
+
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut PeerContext> {
         let identity: Identity = addr.into();
         match identity {
@@ -1584,7 +2187,6 @@ impl Signaling for ResponderSignaling {
 		// A client MUST check that the destination address targets its
 		// assigned identity (or `0x00` during authentication).
         if self.identity() == ClientIdentity::Unknown
-        && !nonce.destination().is_unknown()
         && self.server_handshake_state() != ServerHandshakeState::New {
             // The first message received with a destination address different
             // to `0x00` SHALL be accepted as the client's assigned identity.
@@ -1592,19 +2194,25 @@ impl Signaling for ResponderSignaling {
             // role – responders SHALL ONLY an identity from the range
             // `0x02..0xff`. The identity MUST be stored as the client's
             // assigned identity.
-            if nonce.destination().is_responder() {
-                self.common.identity = ClientIdentity::Responder(nonce.destination().0);
+            //
+            // Note: After the server-hello message, the server must assign
+            // an identity with the next message, so `0x00` is rejected too.
+            let destination = nonce.destination();
+            if destination.is_responder() {
+                self.common.identity = ClientIdentity::Responder(destination.0);
                 debug!("Assigned identity: {}", self.identity());
+            } else if destination.is_initiator() {
+                return Err(ValidationError::Fail(
+                    format!("cannot assign initiator address {} to a responder", destination)
+                ));
             } else {
                 return Err(ValidationError::Fail(
-                    format!("cannot assign address {} to a responder", nonce.destination())
+                    format!("cannot assign server address {} to a responder", destination)
                 ));
             };
         }
         if nonce.destination() != self.identity().into() {
-            return Err(ValidationError::Fail(
-                format!("Bad destination: {} (our identity is {})", nonce.destination(), self.identity())
-            ));
+            return Err(bad_destination(nonce, self.identity()));
         }
 
         Ok(())
@@ -1635,8 +2243,12 @@ impl Signaling for ResponderSignaling {
                 format!("Bad source: {} (our identity is {})", nonce.source(), self.identity())
             )),
 
-            // Required due to https://github.com/rust-lang/rfcs/issues/1550
-            Address(_) => unreachable!(),
+            // Required due to https://github.com/rust-lang/rfcs/issues/1550.
+            // Never panic on input from the network, even if this arm should
+            // become reachable in the future.
+            Address(_) => Err(ValidationError::Fail(
+                format!("Invalid source address: {}", nonce.source())
+            )),
         }
     }
 
@@ -1651,14 +2263,24 @@ impl Signaling for ResponderSignaling {
             InitiatorHandshakeState::KeySent => {
                 // Expect key message, encrypted with our public permanent key
                 // and initiator private permanent key
-                OpenBox::<Message>::decrypt(bbox, &self.common.permanent_keypair, &self.initiator.permanent_key)
+                OpenBox::<Message>::decrypt(
+                    bbox,
+                    &self.common.permanent_keypair,
+                    &self.initiator.permanent_key,
+                    self.common.unknown_fields,
+                )
             },
             InitiatorHandshakeState::AuthSent => {
                 // Expect an auth message, encrypted with our public session
                 // key and initiator private session key
                 let initiator_session_key = self.initiator.session_key.as_ref()
                     .ok_or_else(|| SignalingError::Crash("Initiator session key not set".into()))?;
-                OpenBox::<Message>::decrypt(bbox, &self.initiator.keypair, initiator_session_key)
+                OpenBox::<Message>::decrypt(
+                    bbox,
+                    &self.initiator.keypair,
+                    initiator_session_key,
+                    self.common.unknown_fields,
+                )
             },
             other => {
                 // TODO (#14): Maybe remove these states?
@@ -1674,16 +2296,37 @@ impl Signaling for ResponderSignaling {
     /// context (cookie, CSN, etc).
     fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<Vec<HandleAction>> {
         let old_state = self.initiator.handshake_state();
-        match (old_state, obox.message) {
+        let OpenBox { message, nonce } = obox;
+        let result = match (old_state, message) {
+            (_, ref message) if !message.is_client_to_client() =>
+                Err(not_client_to_client(message.get_type(), Identity::Initiator)),
+
             // Valid state transitions
-            (InitiatorHandshakeState::KeySent, Message::Key(msg)) => self.handle_key(msg, &obox.nonce),
-            (InitiatorHandshakeState::AuthSent, Message::Auth(msg)) => self.handle_auth(msg, obox.nonce.source()),
+            (InitiatorHandshakeState::KeySent, Message::Key(msg)) => self.handle_key(msg, &nonce),
+            (InitiatorHandshakeState::AuthSent, Message::Auth(msg)) => self.handle_auth(msg, nonce.source()),
             (InitiatorHandshakeState::AuthSent, Message::Close(msg)) => self.handle_peer_handshake_close(msg),
 
-            // Any undefined state transition results in an error
-            (s, message) => Err(SignalingError::InvalidStateTransition(
-                format!("Got {} message from initiator in {:?} state", message.get_type(), s)
-            )),
+            // Any undefined state transition is an error
+            (state, message) => Err(invalid_transition(message.get_type(), Identity::Initiator, state)),
+        };
+
+        // Never reuse the session keys of a failed handshake
+        if result.is_err() {
+            self.handle_peer_handshake_failure();
+        }
+
+        result
+    }
+
+    fn handle_peer_handshake_failure(&mut self) {
+        self.reset_session_key();
+    }
+
+    fn expected_peer_message_types(&self) -> Vec<&'static str> {
+        match self.initiator.handshake_state() {
+            InitiatorHandshakeState::KeySent => vec!["key"],
+            InitiatorHandshakeState::AuthSent => vec!["auth", "close"],
+            _ => vec![],
         }
     }
 
@@ -1742,7 +2385,12 @@ impl Signaling for ResponderSignaling {
         // A responder who receives a 'new-initiator' message MUST proceed by
         // deleting all currently cached information about and for the previous
         // initiator (such as cookies and the sequence numbers)...
+        // Our new cookie must not match the cookie of the previous initiator.
+        let previous_cookie = self.initiator.cookie_pair.theirs.take();
         self.initiator = InitiatorContext::new(self.initiator.permanent_key);
+        if let Some(cookie) = previous_cookie {
+            self.initiator.cookie_pair = CookiePair::new_distinct_from(&cookie);
+        }
 
         // ...and continue by sending a 'token' or 'key' client-to-client
         // message described in the Client-to-Client Messages section.
@@ -1769,11 +2417,18 @@ impl Signaling for ResponderSignaling {
         actions.push(self.send_key()?);
         self.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
 
+        // Notify the application about the new peer
+        actions.push(HandleAction::Event(Event::NewPeer(ClientIdentity::Initiator)));
+
         Ok(actions)
     }
 
-    fn handle_new_responder(&mut self, _msg: NewResponder) -> SignalingResult<Vec<HandleAction>> {
-        Err(SignalingError::Protocol("Received 'new-responder' message as responder".into()))
+    fn handle_new_responder(&mut self, msg: NewResponder) -> SignalingResult<Vec<HandleAction>> {
+        if self.strict_protocol {
+            return Err(SignalingError::Protocol("Received 'new-responder' message as responder".into()));
+        }
+        warn!("Ignoring 'new-responder' message ({}) received as responder", msg.id);
+        Ok(vec![])
     }
 
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
@@ -1788,7 +2443,18 @@ impl Signaling for ResponderSignaling {
             ));
         }
 
-        Ok(vec![HandleAction::Event(Event::Disconnected(msg.id.0))])
+        // If the initiator is still in the handshake, reset its context.
+        if self.common().signaling_state() != SignalingState::Task {
+            debug!("Resetting initiator context");
+            self.initiator = InitiatorContext::new(self.initiator.permanent_key);
+        }
+
+        Ok(vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Initiator))])
+    }
+
+    fn handle_send_error_for_peer(&mut self, _address: Address) -> SignalingResult<Vec<HandleAction>> {
+        // The initiator is our only peer
+        Err(SignalingError::SendError)
     }
 }
 
@@ -1809,6 +2475,7 @@ impl ResponderSignaling {
                     Some(token) => AuthProvider::Token(token),
                     None => AuthProvider::TrustedKey(initiator_pubkey),
                 }),
+                auth_token_consumed: false,
                 server: {
                     let mut ctx = ServerContext::new();
                     ctx.permanent_key = server_public_permanent_key;
@@ -1816,13 +2483,32 @@ impl ResponderSignaling {
                 },
                 tasks: Some(tasks),
                 task: None,
+                task_name: None,
                 task_supported_types: None,
                 ping_interval,
+                csn_mode: CsnMode::default(),
+                unknown_fields: UnknownFields::default(),
+                subprotocol: ::SUBPROTOCOL.into(),
+                sent_message_ids: RefCell::new(SentMessageIds::new()),
+                max_dropped_messages: MAX_DROPPED_MESSAGES,
+                dropped_message_counts: HashMap::new(),
+                dropped_messages: vec![],
+                clock: Box::new(SystemClock),
             },
             initiator: InitiatorContext::new(initiator_pubkey),
+            strict_protocol: false,
         }
     }
 
+    /// Drop the public session key of the initiator and generate a new
+    /// session keypair, so that a retried peer handshake never reuses any
+    /// session key material.
+    pub(crate) fn reset_session_key(&mut self) {
+        debug!("Resetting session keys of initiator");
+        self.initiator.session_key = None;
+        self.initiator.keypair = KeyPair::new();
+    }
+
     /// Build a `Token` message.
     ///
     /// The token is consumed to avoid accidentally reusing it.
@@ -1832,12 +2518,14 @@ impl ResponderSignaling {
         let msg: Message = Token {
             key: self.common().permanent_keypair.public_key().to_owned(),
         }.into_message();
-        let nonce = Nonce::new(
+        let nonce = Nonce::new_checked(
             self.initiator.cookie_pair().ours.clone(),
-            self.identity().into(),
+            Address::try_from_client_identity(self.identity())?,
             self.initiator.identity().into(),
             self.initiator.csn_pair().borrow_mut().ours.increment()?,
-        );
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&nonce);
         let obox = OpenBox::<Message>::new(msg, nonce);
 
         // The message SHALL be NaCl secret key encrypted by the token the
@@ -1854,12 +2542,14 @@ impl ResponderSignaling {
         let msg: Message = Key {
             key: self.initiator.keypair.public_key().to_owned(),
         }.into_message();
-        let nonce = Nonce::new(
+        let nonce = Nonce::new_checked(
             self.initiator.cookie_pair().ours.clone(),
-            self.identity().into(),
+            Address::try_from_client_identity(self.identity())?,
             self.initiator.identity().into(),
             self.initiator.csn_pair().borrow_mut().ours.increment()?,
-        );
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&nonce);
         let obox = OpenBox::<Message>::new(msg, nonce);
 
         // The message SHALL be NaCl public-key encrypted by the client's
@@ -1885,12 +2575,23 @@ impl ResponderSignaling {
             return Err(SignalingError::Protocol("Responder session key and permanent key are equal".into()));
         }
 
+        // Ensure that the initiator's session key != our session key
+        if &msg.key == self.initiator.keypair.public_key() {
+            return Err(SignalingError::Protocol("Initiator session key and our session key are equal".into()));
+        }
+
         // Set public session key
         self.initiator.session_key = Some(msg.key);
 
         // State transition
         self.initiator.set_handshake_state(InitiatorHandshakeState::KeyReceived);
 
+        // If we sent a token, the key message of the initiator confirms
+        // that it has been accepted.
+        if self.common().auth_provider.is_none() {
+            self.common_mut().auth_token_consumed = true;
+        }
+
         // Reply with auth msg
         let auth: Message = ResponderAuthBuilder::new(nonce.cookie().clone())
             .add_tasks(
@@ -1901,12 +2602,14 @@ impl ResponderSignaling {
             )
             .build()?
             .into_message();
-        let auth_nonce = Nonce::new(
+        let auth_nonce = Nonce::new_checked(
             self.initiator.cookie_pair().ours.clone(),
-            self.common().identity.into(),
+            Address::try_from_client_identity(self.common().identity)?,
             self.initiator.identity().into(),
             self.initiator.csn_pair().borrow_mut().ours.increment()?,
-        );
+            self.role(),
+        )?;
+        self.common().sent_message_ids.borrow_mut().record(&auth_nonce);
         let obox = OpenBox::<Message>::new(auth, auth_nonce);
         let bbox = obox.encrypt(
             &self.initiator.keypair,
@@ -1984,6 +2687,7 @@ impl ResponderSignaling {
 
         // Store chosen task
         self.common_mut().task_supported_types = Some(chosen_task.supported_types());
+        self.common_mut().task_name = Some(chosen_task.name().into_owned());
         self.common_mut().task = Some(Arc::new(Mutex::new(chosen_task)));
 
         // State transitions
@@ -2007,12 +2711,46 @@ impl ResponderSignaling {
 }
 
 
+/// Return the error for a message that must not be exchanged between clients.
+fn not_client_to_client(msg_type: &str, source: Identity) -> SignalingError {
+    SignalingError::InvalidMessage(format!(
+        "Got '{}' message from {}, but it is not a client-to-client message", msg_type, source
+    ))
+}
+
+/// Return the error for a peer message that is not expected in the current
+/// peer handshake `state`.
+fn invalid_transition<S: fmt::Debug>(msg_type: &str, source: Identity, state: S) -> SignalingError {
+    SignalingError::InvalidStateTransition(
+        format!("Got '{}' message from {} in {:?} state", msg_type, source, state)
+    )
+}
+
+/// Return the validation error for a nonce that is not addressed to us.
+///
+/// Once an identity has been assigned, the error names both the received
+/// and the expected destination address.
+fn bad_destination(nonce: &Nonce, identity: ClientIdentity) -> ValidationError {
+    match identity {
+        ClientIdentity::Unknown => ValidationError::Fail(
+            format!("Bad destination: {} (our identity is {})", nonce.destination(), identity)
+        ),
+        _ => ValidationError::Fail(format!(
+            "Bad destination: {} (expected {}, our identity is {})",
+            nonce.destination(), Address::from(identity), identity
+        )),
+    }
+}
+
 /// Result of the nonce validation.
+#[derive(Debug, PartialEq)]
 pub(crate) enum ValidationError {
     /// Ignore message
     DropMsg(String),
     /// Validation failed
     Fail(String),
+    /// Validation failed because the cookie of the peer has changed
+    CookieChanged(Identity),
     /// A critical error occurred
     Crash(String),
 }
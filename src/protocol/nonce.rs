@@ -12,7 +12,7 @@ use errors::{SignalingError, SignalingResult};
 
 use super::cookie::Cookie;
 use super::csn::CombinedSequenceSnapshot;
-use super::types::{Address, Identity};
+use super::types::{Address, Identity, Role};
 
 
 /// The SaltyRTC nonce.
@@ -20,6 +20,11 @@ use super::types::{Address, Identity};
 /// The type is intentionally non-cloneable, to prevent accidental re-use. All
 /// non-unsafe transformations into other formats consume the instance. This is
 /// also known as an affine type.
+///
+/// Encrypting a message consumes the [`OpenBox`](../../boxes/struct.OpenBox.html)
+/// containing the nonce, so using the same nonce for two different messages
+/// results in a compile time error. The only way around this is the unsafe
+/// [`clone`](#method.clone) method.
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct Nonce {
     cookie: Cookie,
@@ -38,6 +43,39 @@ impl Nonce {
         }
     }
 
+    /// Create a new nonce for an outgoing message, after validating that the
+    /// source and destination addresses are consistent with our role.
+    ///
+    /// Invalid combinations indicate a bug in the implementation and result
+    /// in a `SignalingError::Crash`.
+    pub(crate) fn new_checked(
+        cookie: Cookie,
+        source: Address,
+        destination: Address,
+        csn: CombinedSequenceSnapshot,
+        role: Role,
+    ) -> SignalingResult<Self> {
+        // As long as no identity has been assigned, only the server may be
+        // addressed.
+        if source.is_unknown() && !destination.is_server() {
+            return Err(SignalingError::Crash(
+                format!("Cannot send message from unknown identity to {}", destination)
+            ));
+        }
+        let valid = match role {
+            Role::Initiator => (source.is_unknown() || source.is_initiator())
+                            && (destination.is_server() || destination.is_responder()),
+            Role::Responder => (source.is_unknown() || source.is_responder())
+                            && (destination.is_server() || destination.is_initiator()),
+        };
+        if !valid {
+            return Err(SignalingError::Crash(
+                format!("Invalid nonce addresses for {}: {} -> {}", role, source, destination)
+            ));
+        }
+        Ok(Self::new(cookie, source, destination, csn))
+    }
+
     /// Parse bytes, return a Nonce.
     ///
     /// This will fail if the byte slice does not contain exactly 24 bytes of
@@ -69,10 +107,21 @@ impl Nonce {
     pub(crate) fn into_bytes(self) -> [u8; 24] {
         let mut bytes = [0u8; 24];
         (&mut bytes[0..16]).write_all(self.cookie.as_bytes()).expect("Writing cookie to nonce failed");
-        bytes[16] = self.source.0;
-        bytes[17] = self.destination.0;
-        BigEndian::write_u16(&mut bytes[18..20], self.csn.overflow_number());
-        BigEndian::write_u32(&mut bytes[20..24], self.csn.sequence_number());
+        (&mut bytes[16..24]).write_all(&self.message_id()).expect("Writing message id to nonce failed");
+        bytes
+    }
+
+    /// Return the 8 byte message id.
+    ///
+    /// The id consists of the source address, the destination address, the
+    /// overflow number (2 bytes) and the sequence number (4 bytes), in wire
+    /// order. It is used to identify messages in 'send-error' messages.
+    pub(crate) fn message_id(&self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[0] = self.source.0;
+        bytes[1] = self.destination.0;
+        BigEndian::write_u16(&mut bytes[2..4], self.csn.overflow_number());
+        BigEndian::write_u32(&mut bytes[4..8], self.csn.sequence_number());
         bytes
     }
 
@@ -181,6 +230,132 @@ mod tests {
         assert_eq!(nonce.into_bytes(), create_test_nonce_bytes());
     }
 
+    #[test]
+    fn message_id() {
+        let nonce = create_test_nonce();
+        assert_eq!(nonce.message_id(), [17, 18, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(&nonce.into_bytes()[16..24], &[17, 18, 1, 2, 3, 4, 5, 6]);
+    }
+
+    /// The overflow number and the sequence number are encoded big endian,
+    /// directly following the cookie and the addresses.
+    #[test]
+    fn nonce_byte_positions() {
+        let nonce = Nonce::new(
+            Cookie::new([0xc0; 16]),
+            Address(0x01),
+            Address(0x02),
+            CombinedSequenceSnapshot::new(0x0a0b, 0x0c0d_0e0f),
+        );
+        let bytes = nonce.into_bytes();
+        assert_eq!(&bytes[0..16], &[0xc0; 16]);
+        assert_eq!(bytes[16], 0x01);
+        assert_eq!(bytes[17], 0x02);
+        assert_eq!(bytes[18], 0x0a);
+        assert_eq!(bytes[19], 0x0b);
+        assert_eq!(bytes[20], 0x0c);
+        assert_eq!(bytes[21], 0x0d);
+        assert_eq!(bytes[22], 0x0e);
+        assert_eq!(bytes[23], 0x0f);
+    }
+
+    /// Nonces survive a round trip through the byte representation, including
+    /// the boundary values of the overflow and sequence numbers.
+    #[test]
+    fn nonce_roundtrip() {
+        let csns = [
+            (0, 0), (0, 1), (1, 0),
+            (0xffff, 0), (0, 0xffff_ffff), (0xffff, 0xffff_ffff),
+        ];
+        for &(overflow, sequence) in &csns {
+            let bytes = Nonce::new(
+                Cookie::new([0x42; 16]),
+                Address(0x03),
+                Address(0x01),
+                CombinedSequenceSnapshot::new(overflow, sequence),
+            ).into_bytes();
+            let nonce = Nonce::from_bytes(&bytes).unwrap();
+            assert_eq!(nonce.cookie(), &Cookie::new([0x42; 16]));
+            assert_eq!(nonce.source(), Address(0x03));
+            assert_eq!(nonce.destination(), Address(0x01));
+            assert_eq!(nonce.csn().overflow_number(), overflow);
+            assert_eq!(nonce.csn().sequence_number(), sequence);
+            assert_eq!(nonce.into_bytes(), bytes);
+        }
+    }
+
+    /// Byte slices of the wrong length are rejected with a decoding error.
+    #[test]
+    fn parse_nonce_wrong_length() {
+        let bytes = [0u8; 25];
+        for &len in &[0, 23, 25] {
+            assert_eq!(
+                Nonce::from_bytes(&bytes[..len]),
+                Err(SignalingError::Decode(format!("Byte slice must be exactly 24 bytes, not {}", len)))
+            );
+        }
+    }
+
+    /// Parsing never fails on 24 byte inputs. Any address byte is accepted
+    /// here, invalid combinations are rejected by the nonce validation.
+    #[test]
+    fn parse_nonce_any_address() {
+        let mut bytes = [0xffu8; 24];
+        for address in 0..=255u8 {
+            bytes[16] = address;
+            bytes[17] = address.wrapping_add(1);
+            let nonce = Nonce::from_bytes(&bytes).unwrap();
+            assert_eq!(nonce.source(), Address(address));
+            assert_eq!(nonce.destination(), Address(address.wrapping_add(1)));
+            assert_eq!(nonce.csn().overflow_number(), 0xffff);
+            assert_eq!(nonce.csn().sequence_number(), 0xffff_ffff);
+        }
+    }
+
+    fn new_checked(source: u8, destination: u8, role: Role) -> SignalingResult<Nonce> {
+        Nonce::new_checked(Cookie::random(), Address(source), Address(destination),
+                           CombinedSequenceSnapshot::new(0, 1), role)
+    }
+
+    #[test]
+    fn new_checked_valid() {
+        assert!(new_checked(0x00, 0x00, Role::Initiator).is_ok());
+        assert!(new_checked(0x01, 0x00, Role::Initiator).is_ok());
+        assert!(new_checked(0x01, 0x02, Role::Initiator).is_ok());
+        assert!(new_checked(0x01, 0xff, Role::Initiator).is_ok());
+        assert!(new_checked(0x00, 0x00, Role::Responder).is_ok());
+        assert!(new_checked(0x03, 0x00, Role::Responder).is_ok());
+        assert!(new_checked(0x03, 0x01, Role::Responder).is_ok());
+    }
+
+    #[test]
+    fn new_checked_unknown_source_to_peer() {
+        assert_eq!(
+            new_checked(0x00, 0x01, Role::Responder).unwrap_err(),
+            SignalingError::Crash("Cannot send message from unknown identity to 0x01".into())
+        );
+        assert!(new_checked(0x00, 0x02, Role::Initiator).is_err());
+    }
+
+    #[test]
+    fn new_checked_responder_to_responder() {
+        assert_eq!(
+            new_checked(0x03, 0x04, Role::Responder).unwrap_err(),
+            SignalingError::Crash("Invalid nonce addresses for Responder: 0x03 -> 0x04".into())
+        );
+    }
+
+    #[test]
+    fn new_checked_initiator_to_initiator() {
+        assert!(new_checked(0x01, 0x01, Role::Initiator).is_err());
+    }
+
+    #[test]
+    fn new_checked_wrong_source() {
+        assert!(new_checked(0x02, 0x03, Role::Initiator).is_err());
+        assert!(new_checked(0x01, 0x00, Role::Responder).is_err());
+    }
+
     /// Test conversion from a saltyrtc `Nonce` to a rust sodium `Nonce`.
     #[test]
     fn nonce_into_nonce() {
@@ -1,5 +1,6 @@
 //! Wrapper type for the `id` field of the `send-error` message.
 
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 
 use byteorder::{BigEndian, ByteOrder};
@@ -9,9 +10,13 @@ use serde::de::{Deserialize, Deserializer, Visitor, Unexpected, Error as SerdeEr
 use errors::{SignalingError, SignalingResult};
 use super::Address;
 use super::csn::CombinedSequenceSnapshot;
+use super::nonce::Nonce;
 
 const SEND_ERROR_ID_BYTES: usize = 8;
 
+/// The number of sent message ids that are remembered per destination.
+const MAX_SENT_MESSAGE_IDS: usize = 64;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct SendErrorId {
     pub(crate) source: Address,
@@ -20,6 +25,15 @@ pub(crate) struct SendErrorId {
 }
 
 impl SendErrorId {
+    /// Create a new `SendErrorId` from the nonce of a message.
+    pub(crate) fn from_nonce(nonce: &Nonce) -> Self {
+        SendErrorId {
+            source: nonce.source(),
+            destination: nonce.destination(),
+            csn: nonce.csn().clone(),
+        }
+    }
+
     /// Convert the `SendErrorId` into byte representation.
     pub(crate) fn as_bytes(&self) -> [u8; SEND_ERROR_ID_BYTES] {
         let mut bytes = [0u8; 8];
@@ -84,3 +98,106 @@ impl<'de> Deserialize<'de> for SendErrorId {
         deserializer.deserialize_bytes(SendErrorIdVisitor)
     }
 }
+
+
+/// The ids of the most recently sent peer messages, per destination.
+///
+/// This is used to attribute an incoming `send-error` message to the
+/// message (and therefore the peer) it refers to. Each destination keeps
+/// its own ids, so that a busy peer cannot evict the ids of another one.
+#[derive(Debug, Default)]
+pub(crate) struct SentMessageIds {
+    ids: HashMap<Address, VecDeque<SendErrorId>>,
+}
+
+impl SentMessageIds {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the id of a message that is about to be sent.
+    ///
+    /// If too many ids are stored for the destination of the message, the
+    /// oldest one is forgotten.
+    pub(crate) fn record(&mut self, nonce: &Nonce) {
+        let ids = self.ids.entry(nonce.destination()).or_insert_with(VecDeque::new);
+        if ids.len() >= MAX_SENT_MESSAGE_IDS {
+            ids.pop_front();
+        }
+        ids.push_back(SendErrorId::from_nonce(nonce));
+    }
+
+    /// Remove the specified id. Return whether it was found.
+    pub(crate) fn take(&mut self, id: &SendErrorId) -> bool {
+        let ids = match self.ids.get_mut(&id.destination) {
+            Some(ids) => ids,
+            None => return false,
+        };
+        match ids.iter().position(|sent| sent == id) {
+            Some(index) => {
+                ids.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::protocol::cookie::Cookie;
+
+    use super::*;
+
+    fn nonce(destination: u8, sequence_number: u32) -> Nonce {
+        Nonce::new(
+            Cookie::random(),
+            Address(1),
+            Address(destination),
+            CombinedSequenceSnapshot::new(0, sequence_number),
+        )
+    }
+
+    /// The send-error id matches the message id of the nonce.
+    #[test]
+    fn from_nonce_message_id() {
+        let nonce = nonce(3, 0x0102_0304);
+        assert_eq!(SendErrorId::from_nonce(&nonce).as_bytes(), nonce.message_id());
+    }
+
+    #[test]
+    fn sent_message_ids_take() {
+        let mut sent = SentMessageIds::new();
+        sent.record(&nonce(2, 1));
+        sent.record(&nonce(3, 1));
+
+        let id = SendErrorId::from_nonce(&nonce(3, 1));
+        assert!(sent.take(&id));
+        assert!(!sent.take(&id));
+        assert!(!sent.take(&SendErrorId::from_nonce(&nonce(2, 2))));
+        assert!(sent.take(&SendErrorId::from_nonce(&nonce(2, 1))));
+    }
+
+    #[test]
+    fn sent_message_ids_bounded() {
+        let mut sent = SentMessageIds::new();
+        for i in 0..(MAX_SENT_MESSAGE_IDS as u32 + 1) {
+            sent.record(&nonce(2, i));
+        }
+        assert_eq!(sent.ids[&Address(2)].len(), MAX_SENT_MESSAGE_IDS);
+        assert!(!sent.take(&SendErrorId::from_nonce(&nonce(2, 0))));
+        assert!(sent.take(&SendErrorId::from_nonce(&nonce(2, 1))));
+    }
+
+    /// Messages to one destination do not evict the ids of another one.
+    #[test]
+    fn sent_message_ids_per_destination() {
+        let mut sent = SentMessageIds::new();
+        sent.record(&nonce(3, 1));
+        for i in 0..(MAX_SENT_MESSAGE_IDS as u32 + 1) {
+            sent.record(&nonce(2, i));
+        }
+        assert!(sent.take(&SendErrorId::from_nonce(&nonce(3, 1))));
+    }
+}
@@ -13,6 +13,7 @@ pub enum SignalingState {
 }
 
 impl SignalingState {
+    /// Return whether a transition to the specified state is allowed.
     pub fn may_transition_to(&self, new_state: Self) -> bool {
         match (*self, new_state) {
             (SignalingState::ServerHandshake, SignalingState::PeerHandshake) => true,
@@ -22,6 +23,15 @@ impl SignalingState {
     }
 }
 
+/// A phase of the handshake, used to report stalled handshakes.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum HandshakePhase {
+    /// The server handshake.
+    Server,
+    /// The peer handshake.
+    Peer,
+}
+
 /// The states when doing a handshake with the server.
 ///
 /// The `ClientHello` state is only valid for the responder role, otherwise the
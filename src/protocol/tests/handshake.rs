@@ -0,0 +1,95 @@
+//! End-to-end handshake between an initiator and a responder.
+//!
+//! Both signaling instances are wired together in memory. A mock server
+//! performs the server handshake with each client, and client-to-client
+//! frames are relayed unchanged, just like the real server does.
+
+use ::test_helpers::DummyTask;
+use self::messages::*;
+
+use super::*;
+use super::mock_server::{MockServer, replies};
+
+/// Pass frames through the wire format and let the signaling handle them.
+///
+/// Return all replies.
+fn deliver<S: Signaling>(signaling: &mut S, frames: Vec<ByteBox>) -> Vec<ByteBox> {
+    let mut out = vec![];
+    for bbox in frames {
+        let actions = signaling.handle_frame(&bbox.into_bytes()).unwrap();
+        out.extend(replies(actions));
+    }
+    out
+}
+
+#[test]
+fn initiator_and_responder() {
+    let initiator_ks = KeyPair::new();
+    let initiator_pk = initiator_ks.public_key().clone();
+    let responder_ks = KeyPair::new();
+    let responder_pk = responder_ks.public_key().clone();
+
+    let mut initiator = InitiatorSignaling::new(
+        initiator_ks, Tasks::new(Box::new(DummyTask::new(42))), None, None, None, MAX_RESPONDERS,
+    );
+    let auth_token = AuthToken::from_slice(initiator.auth_token().unwrap().secret_key_bytes()).unwrap();
+    let mut responder = ResponderSignaling::new(
+        responder_ks, initiator_pk.clone(), Some(auth_token), None,
+        Tasks::new(Box::new(DummyTask::new(42))), None,
+    );
+
+    // Server handshake with the initiator
+    let mut initiator_server = MockServer::for_initiator(initiator_pk.clone());
+    let hello = initiator_server.server_hello();
+    let mut frames = deliver(&mut initiator, vec![hello]);
+    assert_eq!(frames.len(), 1);
+    initiator_server.receive_client_auth(frames.remove(0));
+    let server_auth = initiator_server.server_auth_for_initiator(vec![]);
+    assert!(deliver(&mut initiator, vec![server_auth]).is_empty());
+    assert_eq!(initiator.common().signaling_state(), SignalingState::PeerHandshake);
+
+    // Server handshake with the responder
+    let mut responder_server = MockServer::for_responder();
+    let hello = responder_server.server_hello();
+    let mut frames = deliver(&mut responder, vec![hello]);
+    assert_eq!(frames.len(), 2);
+    match responder_server.receive(frames.remove(0)) {
+        Message::ClientHello(ref msg) => assert_eq!(msg.key, responder_pk),
+        other => panic!("Expected client-hello, got {:?}", other),
+    }
+    responder_server.receive_client_auth(frames.remove(0));
+    let server_auth = responder_server.server_auth_for_responder(Address(2), true);
+    let mut to_initiator = deliver(&mut responder, vec![server_auth]);
+    assert_eq!(responder.common().signaling_state(), SignalingState::PeerHandshake);
+
+    // The initiator is notified about the new responder
+    let new_responder = initiator_server.new_responder(Address(2));
+    assert!(deliver(&mut initiator, vec![new_responder]).is_empty());
+
+    // The server relays the peer handshake (token, key, auth) until both
+    // sides stop replying
+    let mut rounds = 0;
+    while !to_initiator.is_empty() {
+        assert!(to_initiator.iter().all(|bbox| bbox.nonce.destination() == Address(1)));
+        let to_responder = deliver(&mut initiator, to_initiator);
+        assert!(to_responder.iter().all(|bbox| bbox.nonce.destination() == Address(2)));
+        to_initiator = deliver(&mut responder, to_responder);
+        rounds += 1;
+        assert!(rounds < 5, "Peer handshake does not terminate");
+    }
+
+    assert_eq!(initiator.common().signaling_state(), SignalingState::Task);
+    assert_eq!(responder.common().signaling_state(), SignalingState::Task);
+    assert_eq!(initiator.common().task_name, Some(DummyTask::name_for(42)));
+    assert_eq!(responder.common().task_name, Some(DummyTask::name_for(42)));
+
+    // Both sides agree on the permanent and session keys
+    let initiator_peer = initiator.get_peer().unwrap();
+    let responder_peer = responder.get_peer().unwrap();
+    assert_eq!(initiator_peer.identity(), Identity::Responder(2));
+    assert_eq!(responder_peer.identity(), Identity::Initiator);
+    assert_eq!(initiator_peer.permanent_key(), Some(&responder_pk));
+    assert_eq!(responder_peer.permanent_key(), Some(&initiator_pk));
+    assert_eq!(initiator_peer.session_key(), responder_peer.keypair().map(KeyPair::public_key));
+    assert_eq!(responder_peer.session_key(), initiator_peer.keypair().map(KeyPair::public_key));
+}
@@ -0,0 +1,132 @@
+//! A mock of the server side of a single client connection.
+
+use self::cookie::Cookie;
+use self::csn::CombinedSequence;
+use self::messages::*;
+use self::send_error::SendErrorId;
+
+use super::*;
+
+/// The server side of a connection with a single client.
+///
+/// The mock holds the server session keypair and cookie and produces
+/// correctly encrypted server messages with an incrementing CSN.
+pub(crate) struct MockServer {
+    /// The server session keypair.
+    pub(crate) session_ks: KeyPair,
+    /// The server cookie.
+    pub(crate) cookie: Cookie,
+    /// The server CSN, incremented for every message.
+    pub(crate) csn: CombinedSequence,
+    /// The address assigned to the client in the server-auth message.
+    pub(crate) client_address: Address,
+    /// The public permanent key of the client.
+    ///
+    /// The server knows the initiator key from the signaling path, the
+    /// responder key is learned from the client-hello message.
+    pub(crate) client_key: Option<PublicKey>,
+    /// The cookie of the client, learned from its first message.
+    pub(crate) client_cookie: Option<Cookie>,
+}
+
+impl MockServer {
+    /// Create a mock server for an initiator with the specified public
+    /// permanent key.
+    pub(crate) fn for_initiator(initiator_key: PublicKey) -> Self {
+        Self::new(Some(initiator_key))
+    }
+
+    /// Create a mock server for a responder.
+    pub(crate) fn for_responder() -> Self {
+        Self::new(None)
+    }
+
+    fn new(client_key: Option<PublicKey>) -> Self {
+        MockServer {
+            session_ks: KeyPair::new(),
+            cookie: Cookie::random(),
+            csn: CombinedSequence::random(),
+            client_address: Address(0),
+            client_key,
+            client_cookie: None,
+        }
+    }
+
+    /// Return the unencrypted server-hello message.
+    pub(crate) fn server_hello(&mut self) -> ByteBox {
+        let msg = ServerHello::new(self.session_ks.public_key().clone()).into_message();
+        let nonce = Nonce::new(self.cookie.clone(), Address(0), Address(0), self.csn.increment().unwrap());
+        OpenBox::<Message>::new(msg, nonce).encode()
+    }
+
+    /// Return a server-auth message for an initiator, assigning the
+    /// initiator address.
+    pub(crate) fn server_auth_for_initiator(&mut self, responders: Vec<Address>) -> ByteBox {
+        self.client_address = Address(0x01);
+        let your_cookie = self.client_cookie.clone().expect("Client cookie not known");
+        self.send(ServerAuth::for_initiator(your_cookie, None, responders).into_message())
+    }
+
+    /// Return a server-auth message for a responder, assigning the
+    /// specified responder address.
+    pub(crate) fn server_auth_for_responder(&mut self, address: Address, initiator_connected: bool) -> ByteBox {
+        self.client_address = address;
+        let your_cookie = self.client_cookie.clone().expect("Client cookie not known");
+        self.send(ServerAuth::for_responder(your_cookie, None, initiator_connected).into_message())
+    }
+
+    /// Return a new-responder message.
+    pub(crate) fn new_responder(&mut self, id: Address) -> ByteBox {
+        self.send(NewResponder { id }.into_message())
+    }
+
+    /// Return a send-error message for the message with the specified nonce.
+    pub(crate) fn send_error(&mut self, nonce: &Nonce) -> ByteBox {
+        self.send(SendError { id: SendErrorId::from_nonce(nonce) }.into_message())
+    }
+
+    /// Encrypt a message for the client.
+    pub(crate) fn send(&mut self, msg: Message) -> ByteBox {
+        let nonce = Nonce::new(self.cookie.clone(), Address(0), self.client_address, self.csn.increment().unwrap());
+        OpenBox::<Message>::new(msg, nonce)
+            .encrypt(&self.session_ks, self.client_key.as_ref().expect("Client key not known"))
+    }
+
+    /// Decode a message from the client to the server.
+    ///
+    /// The client-hello message is expected to be unencrypted, all other
+    /// messages must be encrypted.
+    pub(crate) fn receive(&mut self, bbox: ByteBox) -> Message {
+        assert!(bbox.nonce.destination().is_server(), "Message is not addressed to the server");
+        let obox = match self.client_key {
+            Some(ref client_key) => OpenBox::<Message>::decrypt(bbox, &self.session_ks, client_key, UnknownFields::Reject),
+            None => OpenBox::<Message>::decode(bbox, UnknownFields::Reject),
+        }.expect("Could not decode client message");
+        if let Message::ClientHello(ref msg) = obox.message {
+            self.client_key = Some(msg.key.clone());
+        }
+        self.client_cookie = Some(obox.nonce.cookie().clone());
+        obox.message
+    }
+
+    /// Decode the client-auth message and check the repeated cookie.
+    pub(crate) fn receive_client_auth(&mut self, bbox: ByteBox) -> ClientAuth {
+        match self.receive(bbox) {
+            Message::ClientAuth(msg) => {
+                assert_eq!(msg.your_cookie, self.cookie);
+                msg
+            },
+            other => panic!("Expected client-auth, got {:?}", other),
+        }
+    }
+}
+
+/// Return the reply frames of the actions, ignoring all other actions.
+pub(crate) fn replies(actions: Vec<HandleAction>) -> Vec<ByteBox> {
+    actions.into_iter()
+        .filter_map(|action| match action {
+            HandleAction::Reply(bbox) => Some(bbox),
+            _ => None,
+        })
+        .collect()
+}
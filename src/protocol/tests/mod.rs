@@ -2,8 +2,10 @@
 
 use super::*;
 
+mod mock_server;
 mod validate_nonce;
 mod signaling_messages;
+mod handshake;
 
 #[test]
 fn test_responder_counter() {
@@ -23,3 +25,43 @@ fn test_responder_counter_overflow() {
     );
     assert_eq!(rc.0, ::std::u32::MAX);
 }
+
+/// The debug output contains the state, but no key material.
+#[test]
+fn test_debug_initiator() {
+    let ks = KeyPair::new();
+    let private_key_hex = ks.private_key_hex();
+    let public_key_hex = ks.public_key_hex();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().identity = ClientIdentity::Initiator;
+    s.process_new_responder(Address(0x05)).unwrap();
+    s.process_new_responder(Address(0x02)).unwrap();
+    let auth_token_hex = format!("{:?}", s.auth_token().unwrap().secret_key_bytes());
+
+    let debug = format!("{:?}", s);
+    assert_eq!(
+        debug,
+        "InitiatorSignaling { role: Initiator, identity: Initiator, signaling_state: ServerHandshake, \
+         server_handshake_state: New, responders: [Address(0x02), Address(0x05)], responder: None, task: None }"
+    );
+    assert!(!debug.contains(&private_key_hex));
+    assert!(!debug.contains(&public_key_hex));
+    assert!(!debug.contains(&auth_token_hex));
+}
+
+/// The debug output contains the state, but no key material.
+#[test]
+fn test_debug_responder() {
+    let ks = KeyPair::new();
+    let private_key_hex = ks.private_key_hex();
+    let initiator_pubkey = PublicKey::from_slice(&[0x42; 32]).unwrap();
+    let s = ResponderSignaling::new(ks, initiator_pubkey, Some(AuthToken::new()), None, Tasks(vec![]), None);
+
+    let debug = format!("{:?}", s);
+    assert_eq!(
+        debug,
+        "ResponderSignaling { role: Responder, identity: Unknown, signaling_state: ServerHandshake, \
+         server_handshake_state: New, initiator_handshake_state: New, task: None }"
+    );
+    assert!(!debug.contains(&private_key_hex));
+}
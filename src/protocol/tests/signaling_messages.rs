@@ -1,3 +1,4 @@
+use ::SaltyClient;
 use ::crypto_types::UnsignedKeys;
 use ::test_helpers::{DummyTask, TestRandom};
 use self::cookie::{Cookie, CookiePair};
@@ -5,6 +6,7 @@ use self::csn::{CombinedSequence, CombinedSequenceSnapshot};
 use self::messages::*;
 
 use super::*;
+use super::mock_server::{MockServer, replies};
 
 struct TestContext<S: Signaling> {
     /// Our permanent keypair.
@@ -15,10 +17,26 @@ struct TestContext<S: Signaling> {
     pub our_cookie: Cookie,
     /// The server cookie.
     pub server_cookie: Cookie,
+    /// The server CSN, used by `next_server_msg`.
+    pub server_csn: CombinedSequence,
     /// The signaling instance.
     pub signaling: S,
 }
 
+impl<S: Signaling> TestContext<S> {
+    /// Build the next message coming from the server, addressed to us.
+    ///
+    /// In contrast to the `TestMsgBuilder`, the server CSN is tracked and
+    /// incremented across calls.
+    fn next_server_msg(&mut self, msg: Message) -> ByteBox {
+        let csn = self.server_csn.increment().expect("Server CSN overflow");
+        TestMsgBuilder::new(msg)
+            .from(0)
+            .to(Address::from(self.signaling.identity()).0)
+            .build_with_csn(self.server_cookie.clone(), &self.server_ks, self.our_ks.public_key(), csn)
+    }
+}
+
 impl TestContext<InitiatorSignaling> {
     fn initiator(
             identity: ClientIdentity,
@@ -32,7 +50,7 @@ impl TestContext<InitiatorSignaling> {
         let server_cookie = Cookie::random();
         let ks = KeyPair::from_private_key(our_ks.private_key().clone());
         let tasks = Tasks::new(Box::new(DummyTask::new(42)));
-        let mut signaling = InitiatorSignaling::new(ks, tasks, peer_trusted_pubkey, None, None);
+        let mut signaling = InitiatorSignaling::new(ks, tasks, peer_trusted_pubkey, None, None, MAX_RESPONDERS);
         signaling.common_mut().identity = identity;
         signaling.server_mut().set_handshake_state(server_handshake_state);
         signaling.server_mut().cookie_pair = CookiePair {
@@ -47,6 +65,7 @@ impl TestContext<InitiatorSignaling> {
             server_ks,
             our_cookie,
             server_cookie,
+            server_csn: CombinedSequence::random(),
             signaling,
         }
     }
@@ -87,6 +106,7 @@ impl TestContext<ResponderSignaling> {
             server_ks,
             our_cookie,
             server_cookie,
+            server_csn: CombinedSequence::random(),
             signaling,
         }
     }
@@ -149,6 +169,61 @@ impl TestMsgBuilder {
     }
 }
 
+mod decode_errors {
+    use rmp_serde as rmps;
+
+    use super::*;
+
+    /// An undecodable server-hello surfaces as `SignalingError::Decode`.
+    #[test]
+    fn server_hello_invalid_fields() {
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
+        );
+        let msg = Value::Map(vec![(Value::from("type"), Value::from("server-hello"))]);
+        let bytes = rmps::to_vec_named(&msg).unwrap();
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+
+        let err = s.handle_message(ByteBox::new(bytes, nonce)).unwrap_err();
+        match err {
+            SignalingError::Decode(ref msg) => assert!(msg.starts_with("Cannot decode message payload")),
+            ref other => panic!("Expected Decode error, got {:?}", other),
+        }
+        assert!(!err.is_fatal());
+        match SaltyError::from(err) {
+            SaltyError::Decode(_) => {},
+            other => panic!("Expected Decode error, got {:?}", other),
+        }
+    }
+
+    /// A server message that cannot be decrypted surfaces as
+    /// `SignalingError::DecryptionFailed`.
+    #[test]
+    fn server_auth_cannot_decrypt() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, vec![]).into_message();
+        let mut bbox = ctx.next_server_msg(msg);
+        bbox.bytes[0] ^= 0xff;
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::DecryptionFailed { from: Identity::Server });
+        assert!(!err.is_fatal());
+        match SaltyError::from(err) {
+            SaltyError::Crypto(_) => {},
+            other => panic!("Expected Crypto error, got {:?}", other),
+        }
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
+    }
+}
+
 mod server_auth {
     use super::*;
 
@@ -182,6 +257,147 @@ mod server_auth {
         assert_eq!(s.identity(), ClientIdentity::Responder(13));
     }
 
+    // A second 'server-auth' message after the server handshake is done
+    // results in a disconnect with close code 3001 (Protocol Error).
+    #[test]
+    fn second_server_auth() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        // Prepare a ServerAuth message
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, vec![]).into_message();
+        let bbox = ctx.next_server_msg(msg);
+
+        // Handle message
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Disconnect(CloseCode::ProtocolError)]);
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::Done);
+    }
+
+    // An empty responders list is valid. The server handshake must still
+    // complete and the initiator must accept subsequent 'new-responder'
+    // messages.
+    #[test]
+    fn initiator_empty_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+
+        // Handle server-auth without any responders
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, vec![]).into_message();
+        let bbox = ctx.next_server_msg(msg);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::ServerHandshakeDone(true))]);
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::Done);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert!(ctx.signaling.responders.is_empty());
+
+        // A new responder is accepted
+        let msg = Message::NewResponder(NewResponder { id: Address(3) });
+        let bbox = ctx.next_server_msg(msg);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(3)))]);
+        assert_eq!(ctx.signaling.responders.len(), 1);
+        assert!(ctx.signaling.responders.contains_key(&Address(3)));
+    }
+
+    // The session summary reflects the state after server-auth.
+    #[test]
+    fn summary_initiator() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        assert_eq!(ctx.signaling.peer_count(), 0);
+
+        let responders = vec![Address(2), Address(3)];
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, responders).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+        let _actions = ctx.signaling.handle_message(bbox).unwrap();
+
+        assert_eq!(ctx.signaling.peer_count(), 2);
+        assert_eq!(ctx.signaling.summary(), SessionSummary {
+            role: Role::Initiator,
+            identity: ClientIdentity::Initiator,
+            signaling_state: SignalingState::PeerHandshake,
+            server_handshake_state: ServerHandshakeState::Done,
+            responder_count: 2,
+        });
+    }
+
+    // The session summary reflects the state after server-auth.
+    #[test]
+    fn summary_responder() {
+        let ctx = TestContext::responder(
+            ClientIdentity::Unknown,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None
+        );
+
+        let msg = ServerAuth::for_responder(ctx.our_cookie.clone(), None, true).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(13).build_from_server(&ctx);
+        let mut s = ctx.signaling;
+        assert_eq!(s.peer_count(), 0);
+        let _actions = s.handle_message(bbox).unwrap();
+
+        assert_eq!(s.peer_count(), 1);
+        assert_eq!(s.summary(), SessionSummary {
+            role: Role::Responder,
+            identity: ClientIdentity::Responder(13),
+            signaling_state: SignalingState::PeerHandshake,
+            server_handshake_state: ServerHandshakeState::Done,
+            responder_count: 0,
+        });
+    }
+
+    // The handshake predicates flip once the respective handshake is done.
+    #[test]
+    fn handshake_done_predicates() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        assert!(!ctx.signaling.is_server_handshake_done());
+        assert!(!ctx.signaling.is_handshake_done());
+
+        // Handle server-auth
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, vec![]).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+        ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert!(ctx.signaling.is_server_handshake_done());
+        assert!(!ctx.signaling.is_handshake_done());
+
+        // Finish peer handshake
+        ctx.signaling.common_mut().set_signaling_state(SignalingState::Task).unwrap();
+        assert!(ctx.signaling.is_server_handshake_done());
+        assert!(ctx.signaling.is_handshake_done());
+    }
+
+    // The assigned identity is exposed through the public client API.
+    #[test]
+    fn client_identity() {
+        // Initialize signaling class
+        let ctx = TestContext::responder(
+            ClientIdentity::Unknown,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None
+        );
+
+        // Prepare a ServerAuth message
+        let msg = ServerAuth::for_responder(ctx.our_cookie.clone(), None, false).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(13).build_from_server(&ctx);
+
+        // Handle message
+        let mut client = SaltyClient { signaling: Box::new(ctx.signaling) };
+        assert_eq!(client.identity(), ClientIdentity::Unknown);
+        let _actions = client.handle_message(bbox).unwrap();
+        assert_eq!(client.identity(), ClientIdentity::Responder(13));
+    }
+
     // The peer MUST check that the cookie provided in the your_cookie
     // field contains the cookie the client has used in its
     // previous and messages to the server.
@@ -277,7 +493,22 @@ mod server_auth {
         // Handle message
         assert_client_info_sent_fail(&mut ctx, bbox,
                                      SignalingError::InvalidMessage(
-                                         "`responders` field in server-auth message may not contain addresses <0x02".into()));
+                                         "`responders` field in server-auth message may not contain addresses <0x02, found 0x01".into()));
+    }
+
+    /// The server address is reserved as well and may not appear in the
+    /// `responders` field.
+    #[test]
+    fn initiator_server_address_in_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, vec![Address(2), Address(0)]).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+        assert_client_info_sent_fail(&mut ctx, bbox,
+                                     SignalingError::InvalidMessage(
+                                         "`responders` field in server-auth message may not contain addresses <0x02, found 0x00".into()));
     }
 
     /// The client SHOULD store the responder's identities in its internal
@@ -381,6 +612,58 @@ mod server_auth {
         assert_eq!(actions[1], HandleAction::Event(Event::ServerHandshakeDone(true)));
     }
 
+    /// A responder that trusts the initiator's permanent key skips the token
+    /// and sends the key message right away.
+    #[test]
+    fn respond_initiator_trusted() {
+        let initiator_ks = KeyPair::new();
+        let ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            Some(initiator_ks.public_key().clone()), None,
+        );
+        match ctx.signaling.common().auth_provider {
+            Some(AuthProvider::TrustedKey(ref key)) => assert_eq!(key, initiator_ks.public_key()),
+            _ => panic!("Expected a trusted key auth provider"),
+        }
+        let our_pk = ctx.our_ks.public_key().clone();
+        let mut actions = _server_auth_respond(ctx);
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1], HandleAction::Event(Event::ServerHandshakeDone(true)));
+
+        // The first message is a key message, encrypted with the permanent keys
+        let key = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &initiator_ks, &our_pk, UnknownFields::Ignore,
+            ).expect("Could not decrypt key message").message,
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(key.get_type(), "key");
+    }
+
+    /// A responder can be assigned the highest responder address.
+    #[test]
+    fn responder_max_address() {
+        let ctx = TestContext::responder(
+            ClientIdentity::Unknown,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        let msg = ServerAuth {
+            your_cookie: ctx.our_cookie.clone(),
+            signed_keys: None,
+            responders: None,
+            initiator_connected: Some(false),
+        }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(0xff).build_from_server(&ctx);
+
+        let mut s = ctx.signaling;
+        let actions = s.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::ServerHandshakeDone(false))]);
+        assert_eq!(s.identity(), ClientIdentity::Responder(0xff));
+        assert_eq!(s.server().handshake_state(), ServerHandshakeState::Done);
+    }
+
     /// If processing the server auth message succeeds, the signaling state
     /// should change to `PeerHandshake`.
     #[test]
@@ -498,16 +781,48 @@ mod server_auth {
 mod client_auth {
     use super::*;
 
+    /// Messages to the server after server-auth are encrypted with the
+    /// server session key, even if the server permanent key is known.
+    #[test]
+    fn after_server_auth_uses_session_key() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let server_permanent_ks = KeyPair::new();
+        ctx.signaling.common_mut().server.permanent_key = Some(server_permanent_ks.public_key().clone());
+
+        let bbox = match ctx.signaling.send_drop_responder(Address(2), DropReason::DroppedByInitiator).unwrap() {
+            HandleAction::Reply(bbox) => bbox,
+            other => panic!("Expected Reply, got {:?}", other),
+        };
+        let bytes = bbox.into_bytes();
+
+        let bbox = ByteBox::from_slice(&bytes).unwrap();
+        assert_eq!(
+            OpenBox::<Message>::decrypt(bbox, &server_permanent_ks, ctx.our_ks.public_key(), UnknownFields::Ignore),
+            Err(SignalingError::DecryptionFailed { from: Identity::Initiator })
+        );
+        let bbox = ByteBox::from_slice(&bytes).unwrap();
+        let obox = OpenBox::<Message>::decrypt(bbox, &ctx.server_ks, ctx.our_ks.public_key(), UnknownFields::Ignore).unwrap();
+        assert_eq!(obox.message, DropResponder::with_reason(Address(2), DropReason::DroppedByInitiator).into_message());
+    }
+
     fn _test_ping_interval(interval: Option<Duration>) -> ClientAuth {
         let kp = KeyPair::new();
-        let mut s = InitiatorSignaling::new(
+        let s = InitiatorSignaling::new(
             kp,
             Tasks::new(Box::new(DummyTask::new(123))),
             None,
             None,
             interval,
+            MAX_RESPONDERS,
         );
+        _handle_server_hello(s)
+    }
 
+    /// Handle a server-hello message and return the client-auth reply.
+    fn _handle_server_hello(mut s: InitiatorSignaling) -> ClientAuth {
         // Create and encode ServerHello message
         let server_pubkey = PublicKey::random();
         let server_hello = ServerHello::new(server_pubkey.clone()).into_message();
@@ -531,10 +846,11 @@ mod client_auth {
             HandleAction::HandshakeError(_) => panic!("Unexpected HandshakeError"),
             HandleAction::TaskMessage(_) => panic!("Unexpected TaskMessage"),
             HandleAction::Event(_) => panic!("Unexpected Event"),
+            HandleAction::Disconnect(_) => panic!("Unexpected Disconnect"),
         };
 
         let decrypted = OpenBox::<Message>::decrypt(
-            bytes, &s.common().permanent_keypair, &server_pubkey
+            bytes, &s.common().permanent_keypair, &server_pubkey, UnknownFields::Ignore
         ).unwrap();
         match decrypted.message {
             Message::ClientAuth(client_auth) => client_auth,
@@ -542,6 +858,132 @@ mod client_auth {
         }
     }
 
+    /// The server keys can be queried after handling a server-hello.
+    #[test]
+    fn server_keys() {
+        let server_permanent_key = KeyPair::new().public_key().clone();
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            Some(server_permanent_key.clone()),
+            None,
+            MAX_RESPONDERS,
+        );
+        assert_eq!(s.server_session_key(), None);
+
+        let server_hello = ServerHello::random();
+        let server_session_key = server_hello.key.clone();
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+        s.handle_message(OpenBox::<Message>::new(server_hello.into_message(), nonce).encode()).unwrap();
+
+        assert_eq!(s.server_session_key(), Some(server_session_key));
+        assert_eq!(s.server_permanent_key(), Some(server_permanent_key));
+    }
+
+    /// A raw server-hello frame can be handled with `handle_frame`.
+    #[test]
+    fn handle_frame() {
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
+        );
+        let server_hello = ServerHello::random().into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+        let frame = OpenBox::<Message>::new(server_hello, nonce).encode().into_bytes();
+
+        let actions = s.handle_frame(&frame).unwrap();
+        assert_eq!(s.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
+        assert_eq!(actions.len(), 1); // Reply with client-auth
+    }
+
+    /// Frames that are too short are rejected with a decoding error.
+    #[test]
+    fn handle_frame_too_short() {
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
+        );
+        assert_eq!(
+            s.handle_frame(&[0; 24]),
+            Err(SignalingError::Decode("Message is too short".into()))
+        );
+        assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+    }
+
+    /// A second unencrypted server-hello is rejected and does not overwrite
+    /// the stored server session key.
+    #[test]
+    fn duplicate_server_hello() {
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
+        );
+        let cookie = Cookie::random();
+        let mut csn = CombinedSequence::random();
+
+        // First server-hello
+        let first = ServerHello::random();
+        let first_key = first.key.clone();
+        let nonce = Nonce::new(cookie.clone(), Address(0), Address(0), csn.increment().unwrap());
+        s.handle_message(OpenBox::<Message>::new(first.into_message(), nonce).encode()).unwrap();
+        assert_eq!(s.server().session_key, Some(first_key.clone()));
+
+        // Second server-hello
+        let nonce = Nonce::new(cookie, Address(0), Address(0), csn.increment().unwrap());
+        let bbox = OpenBox::<Message>::new(ServerHello::random().into_message(), nonce).encode();
+        assert_eq!(
+            s.handle_message(bbox),
+            Err(SignalingError::Protocol("Expected encrypted message from server in state ClientInfoSent".into()))
+        );
+        assert_eq!(s.server().session_key, Some(first_key));
+        assert_eq!(s.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
+    }
+
+    /// An encrypted server-hello after the first one is rejected as well.
+    #[test]
+    fn duplicate_server_hello_encrypted() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let msg = ServerHello::random().into_message();
+        let bbox = ctx.next_server_msg(msg);
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::Protocol("Got a second 'server-hello' message from server in ClientInfoSent state".into()))
+        );
+        assert_eq!(ctx.signaling.server().session_key, Some(ctx.server_ks.public_key().clone()));
+    }
+
+    /// Handling a server-hello when the server session key is already set
+    /// fails without overwriting it.
+    #[test]
+    fn server_hello_session_key_already_set() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::New,
+        );
+        assert_eq!(
+            ctx.signaling.handle_server_hello(ServerHello::random()),
+            Err(SignalingError::Protocol("Got a server-hello message in New state, but server session key is already set".into()))
+        );
+        assert_eq!(ctx.signaling.server().session_key, Some(ctx.server_ks.public_key().clone()));
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::New);
+    }
+
     /// If ping interval is None, send zero.
     #[test]
     fn ping_interval_none() {
@@ -569,34 +1011,122 @@ mod client_auth {
         let client_auth = _test_ping_interval(Some(Duration::new(123, 45)));
         assert_eq!(client_auth.ping_interval, 123);
     }
-}
 
-mod token {
-    use super::*;
+    /// By default, the SaltyRTC v1 subprotocol is requested.
+    #[test]
+    fn subprotocol_default() {
+        let client_auth = _test_ping_interval(None);
+        assert_eq!(client_auth.subprotocols, vec!["v1.saltyrtc.org".to_string()]);
+    }
 
-    /// A receiving initiator MUST check that the message contains a valid NaCl
-    /// public key (32 bytes) in the key field.
+    /// A custom subprotocol replaces the default subprotocol.
     #[test]
-    fn token_initiator_validate_public_key() {
-        let mut ctx = TestContext::initiator(
-            ClientIdentity::Initiator, None,
-            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+    fn subprotocol_custom() {
+        let mut s = InitiatorSignaling::new(
+            KeyPair::new(),
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            None,
+            None,
+            MAX_RESPONDERS,
         );
+        s.common_mut().subprotocol = "v1.custom.example.org".into();
+        let client_auth = _handle_server_hello(s);
+        assert_eq!(client_auth.subprotocols, vec!["v1.custom.example.org".to_string()]);
+    }
 
-        // Create new responder context
-        let addr = Address(3);
-        let responder = ResponderContext::new(addr, 0);
-        ctx.signaling.responders.insert(addr, responder);
+    /// A responder must send the client-hello message before the
+    /// client-auth message.
+    #[test]
+    fn responder_client_hello_before_client_auth() {
+        let kp = KeyPair::new();
+        let initiator_pubkey = PublicKey::random();
+        let mut s = ResponderSignaling::new(kp, initiator_pubkey, None, None, Tasks(vec![]), None);
 
-        // Prepare a token message
-        let msg_bytes = [
-            // Fixmap with two entries
-            0x82,
-            // Key: type
-            0xa4, 0x74, 0x79, 0x70, 0x65,
-            // Val: send-error
-            0xa5, 0x74, 0x6f, 0x6b, 0x65, 0x6e,
-            // Key: key
+        // Create and encode ServerHello message
+        let server_pubkey = PublicKey::random();
+        let server_hello = ServerHello::new(server_pubkey.clone()).into_message();
+        let server_cookie = Cookie::random();
+        let cs = CombinedSequenceSnapshot::random();
+        let nonce = Nonce::new(server_cookie.clone(), Address(0), Address(0), cs);
+        let bbox = OpenBox::<Message>::new(server_hello, nonce).encode();
+
+        // Handle message
+        let mut actions = s.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 2);
+        let first = match actions.remove(0) {
+            HandleAction::Reply(bbox) => bbox,
+            other => panic!("Expected Reply, got {:?}", other),
+        };
+        let second = match actions.remove(0) {
+            HandleAction::Reply(bbox) => bbox,
+            other => panic!("Expected Reply, got {:?}", other),
+        };
+
+        // The first message is an unencrypted client-hello
+        let client_hello = OpenBox::<Message>::decode(first, UnknownFields::Ignore).unwrap();
+        match client_hello.message {
+            Message::ClientHello(ref msg) => assert_eq!(&msg.key, s.common().permanent_keypair.public_key()),
+            ref other => panic!("Expected ClientHello, got {:?}", other),
+        }
+
+        // The second message is an encrypted client-auth
+        let client_auth = OpenBox::<Message>::decrypt(
+            second, &s.common().permanent_keypair, &server_pubkey, UnknownFields::Ignore
+        ).unwrap();
+        match client_auth.message {
+            Message::ClientAuth(ref msg) => {
+                assert_eq!(msg.your_cookie, server_cookie);
+                assert_eq!(msg.subprotocols, vec![::SUBPROTOCOL.to_string()]);
+                assert_eq!(msg.ping_interval, 0);
+                assert_eq!(msg.your_key, None);
+            },
+            ref other => panic!("Expected ClientAuth, got {:?}", other),
+        }
+
+        // Both messages are sent from the unknown address to the server,
+        // using the same cookie
+        for nonce in &[&client_hello.nonce, &client_auth.nonce] {
+            assert_eq!(nonce.source(), Address(0));
+            assert_eq!(nonce.destination(), Address(0));
+            assert_eq!(nonce.cookie(), &s.server().cookie_pair().ours);
+        }
+        assert_ne!(&server_cookie, &s.server().cookie_pair().ours);
+
+        // The CSN is incremented in the same order
+        assert_eq!(
+            client_hello.nonce.csn().combined_sequence_number() + 1,
+            client_auth.nonce.csn().combined_sequence_number()
+        );
+    }
+}
+
+mod token {
+    use super::*;
+
+    /// A receiving initiator MUST check that the message contains a valid NaCl
+    /// public key (32 bytes) in the key field.
+    #[test]
+    fn token_initiator_validate_public_key() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        // Create new responder context
+        let addr = Address(3);
+        let responder = ResponderContext::new(addr, 0);
+        ctx.signaling.responders.insert(addr, responder);
+
+        // Prepare a token message
+        let msg_bytes = [
+            // Fixmap with two entries
+            0x82,
+            // Key: type
+            0xa4, 0x74, 0x79, 0x70, 0x65,
+            // Val: send-error
+            0xa5, 0x74, 0x6f, 0x6b, 0x65, 0x6e,
+            // Key: key
             0xa3, 0x6b, 0x65, 0x79,
             // Val: 3 bytes
             0xc4, 0x03, 0x00, 0x01, 0x02,
@@ -615,7 +1145,7 @@ mod token {
         // Handle message. This should result in a decoding error
         let err = ctx.signaling.handle_message(bbox).unwrap_err();
         assert_eq!(err, SignalingError::Decode(
-            "Cannot decode message payload: Decoding error: Could not decode msgpack data: error while decoding value".into()
+            "Cannot decode message payload: Decoding error: Could not decode 'token' message: error while decoding value".into()
         ));
     }
 
@@ -626,7 +1156,28 @@ mod token {
     /// field.
     #[test]
     fn token_initiator_cannot_decrypt() {
-        // TODO (#19)!
+        let ks = KeyPair::new();
+        let pk = ks.public_key().clone();
+        let mut s = InitiatorSignaling::new(ks, Tasks::new(Box::new(DummyTask::new(42))), None, None, None, MAX_RESPONDERS);
+
+        // Server handshake, with responder 0x03 already connected
+        let mut server = MockServer::for_initiator(pk);
+        let mut frames = replies(s.handle_message(server.server_hello()).unwrap());
+        server.receive_client_auth(frames.remove(0));
+        s.handle_message(server.server_auth_for_initiator(vec![Address(3)])).unwrap();
+
+        // Token message encrypted with a different auth token
+        let msg_bytes = Token { key: PublicKey::random() }.into_message().to_msgpack();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::random());
+        let encrypted = AuthToken::new().encrypt(&msg_bytes, unsafe { nonce.clone() });
+        let mut actions = s.handle_message(ByteBox::new(encrypted, nonce)).unwrap();
+
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions.pop(), Some(HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(3)))));
+        assert_eq!(
+            server.receive(replies(actions).remove(0)),
+            DropResponder::with_reason(Address(3), DropReason::InitiatorCouldNotDecrypt).into_message()
+        );
     }
 
     /// If a token message is valid, set the responder permanent key.
@@ -673,6 +1224,99 @@ mod token {
             assert_eq!(actions, vec![]);
         }
     }
+
+    /// Build a token message from the specified responder.
+    fn _token_msg(ctx: &TestContext<InitiatorSignaling>, addr: Address, pk: PublicKey) -> ByteBox {
+        let msg_bytes = Token { key: pk }.into_message().to_msgpack();
+        let nonce = Nonce::new(Cookie::random(), addr, Address(1), CombinedSequenceSnapshot::random());
+        let encrypted = ctx.signaling
+            .auth_token().expect("Could not get auth token")
+            .encrypt(&msg_bytes, unsafe { nonce.clone() });
+        ByteBox::new(encrypted, nonce)
+    }
+
+    /// The auth token is consumed once the initiator has received a token
+    /// message.
+    #[test]
+    fn token_initiator_auth_token_consumed() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        assert!(!ctx.signaling.auth_token_consumed());
+
+        let bbox = _token_msg(&ctx, Address(3), PublicKey::random());
+        ctx.signaling.handle_message(bbox).unwrap();
+        assert!(ctx.signaling.auth_token_consumed());
+    }
+
+    /// The auth token of the responder is consumed once the initiator has
+    /// replied to the token message with its key message.
+    #[test]
+    fn token_responder_auth_token_consumed() {
+        let initiator_ks = KeyPair::new();
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            Some(initiator_ks.public_key().clone()), Some(AuthToken::new()),
+        );
+
+        // Send token
+        let msg = ServerAuth {
+            your_cookie: ctx.our_cookie.clone(),
+            signed_keys: None,
+            responders: None,
+            initiator_connected: Some(true),
+        }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(7).build_from_server(&ctx);
+        assert_eq!(ctx.signaling.handle_message(bbox).unwrap().len(), 3);
+        assert!(!ctx.signaling.auth_token_consumed());
+
+        // Receive key
+        let msg = Key { key: PublicKey::random() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(7)
+            .build(Cookie::random(), &initiator_ks, ctx.our_ks.public_key());
+        ctx.signaling.handle_message(bbox).unwrap();
+        assert!(ctx.signaling.auth_token_consumed());
+    }
+
+    /// With an allowlist, only responders with listed keys are accepted.
+    /// Others are dropped with close code 3007.
+    #[test]
+    fn token_initiator_allowed_responder_keys() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let allowed_pk = PublicKey::random();
+        let other_pk = PublicKey::random();
+        ctx.signaling.allowed_responder_keys = Some(vec![allowed_pk.clone()].into_iter().collect());
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        ctx.signaling.process_new_responder(Address(4)).unwrap();
+
+        // Unlisted key: Responder is dropped
+        let bbox = _token_msg(&ctx, Address(4), other_pk);
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1], HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(4))));
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), UnknownFields::Ignore,
+            ).unwrap().message,
+            other => panic!("Expected reply, got {:?}", other),
+        };
+        assert_eq!(drop_responder, DropResponder::with_reason(Address(4), DropReason::InvalidKey).into_message());
+        assert!(!ctx.signaling.responders.contains_key(&Address(4)));
+
+        // The auth token is still valid, listed key is accepted
+        let bbox = _token_msg(&ctx, Address(3), allowed_pk.clone());
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![]);
+        let responder = ctx.signaling.responders.get(&Address(3)).unwrap();
+        assert_eq!(responder.handshake_state(), ResponderHandshakeState::TokenReceived);
+        assert_eq!(responder.permanent_key, Some(allowed_pk));
+    }
 }
 
 mod key {
@@ -727,6 +1371,67 @@ mod key {
         }
     }
 
+    /// An untrusted responder must send a token message before its key
+    /// message.
+    #[test]
+    fn key_initiator_before_token() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+
+        // Key message encrypted with the auth token
+        let msg_bytes = Key { key: PublicKey::random() }.into_message().to_msgpack();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::random());
+        let encrypted = ctx.signaling
+            .auth_token().expect("Could not get auth token")
+            .encrypt(&msg_bytes, unsafe { nonce.clone() });
+        let bbox = ByteBox::new(encrypted, nonce);
+
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::InvalidStateTransition(
+                "Got key message from responder 0x03 before token message".into()
+            ))
+        );
+    }
+
+    /// A token message followed by a key message is accepted.
+    #[test]
+    fn key_initiator_after_token() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        let peer_ks = KeyPair::new();
+        let cookie = Cookie::random();
+
+        // Token message encrypted with the auth token
+        let msg_bytes = Token { key: peer_ks.public_key().clone() }.into_message().to_msgpack();
+        let nonce = Nonce::new(cookie.clone(), Address(3), Address(1), CombinedSequenceSnapshot::new(0, 1));
+        let encrypted = ctx.signaling
+            .auth_token().expect("Could not get auth token")
+            .encrypt(&msg_bytes, unsafe { nonce.clone() });
+        ctx.signaling.handle_message(ByteBox::new(encrypted, nonce)).unwrap();
+        assert_eq!(
+            ctx.signaling.responders.get(&Address(3)).unwrap().handshake_state(),
+            ResponderHandshakeState::TokenReceived
+        );
+
+        // Key message encrypted with the permanent keys
+        let msg: Message = Key { key: PublicKey::random() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build_with_csn(cookie, &peer_ks, ctx.our_ks.public_key(), CombinedSequenceSnapshot::new(0, 2));
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 1); // Reply with key msg
+        assert_eq!(
+            ctx.signaling.responders.get(&Address(3)).unwrap().handshake_state(),
+            ResponderHandshakeState::KeySent
+        );
+    }
+
     /// The client MUST generate a session key pair (a new NaCl key pair
     /// for public key authenticated encryption) for further communication
     /// with the other client. The client's session key pair SHALL NOT be
@@ -763,6 +1468,109 @@ mod key {
         assert_eq!(ctx.signaling.initiator.session_key, Some(peer_session_pk));
         assert_eq!(actions.len(), 1); // Reply with auth msg
     }
+
+    /// The session key of the responder must differ from the session key
+    /// generated by the initiator.
+    #[test]
+    fn key_initiator_equal_session_keys() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let peer_permanent_pk = PublicKey::random();
+
+        // Create new responder context
+        let addr = Address(3);
+        let mut responder = ResponderContext::new(addr, 0);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        responder.permanent_key = Some(peer_permanent_pk.clone());
+
+        // The key message contains our own session key
+        let msg: Message = Key { key: responder.keypair.public_key().clone() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1).build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+        ctx.signaling.responders.insert(addr, responder);
+
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::Protocol("Responder session key and our session key are equal".into()))
+        );
+        assert_eq!(ctx.signaling.responders.get(&addr).unwrap().session_key, None);
+    }
+
+    /// The session key of the initiator must differ from the session key
+    /// generated by the responder.
+    #[test]
+    fn key_responder_equal_session_keys() {
+        let peer_permanent_pk = PublicKey::random();
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(6),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(peer_permanent_pk), None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+
+        // The key message contains our own session key
+        let msg: Message = Key { key: ctx.signaling.initiator.keypair.public_key().clone() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(6).build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::Protocol("Initiator session key and our session key are equal".into()))
+        );
+        assert_eq!(ctx.signaling.initiator.session_key, None);
+    }
+
+    /// If the key message cannot be decrypted, the responder drops the
+    /// session key of the initiator and generates a new session keypair, so
+    /// that the key step can be retried without reusing any key material.
+    #[test]
+    fn key_responder_failure_resets_session_key() {
+        // Peer crypto
+        let peer_permanent_pk = PublicKey::random();
+        let peer_session_ks = KeyPair::new();
+        let cookie = Cookie::random();
+        let mut csn = CombinedSequence::random();
+
+        // Context
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(6),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(peer_permanent_pk), None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        let old_keypair = ctx.signaling.initiator.keypair.public_key().clone();
+
+        // Prepare a key message
+        let msg: Message = Key { key: peer_session_ks.public_key().clone() }.into_message();
+
+        // Encrypt it with the wrong keypair
+        let bbox = TestMsgBuilder::new(msg.clone()).from(1).to(6)
+            .build_with_csn(cookie.clone(), &KeyPair::new(), &peer_permanent_pk, csn.increment().unwrap());
+        match ctx.signaling.handle_message(bbox) {
+            Err(SignalingError::DecryptionFailed { .. }) => {},
+            other => panic!("Expected a decryption failure, got {:?}", other),
+        }
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
+        assert_eq!(ctx.signaling.initiator.session_key, None);
+        let new_keypair = ctx.signaling.initiator.keypair.public_key().clone();
+        assert_ne!(new_keypair, old_keypair);
+
+        // Retry the key step
+        let bbox = TestMsgBuilder::new(msg).from(1).to(6)
+            .build_with_csn(cookie, &ctx.our_ks, &peer_permanent_pk, csn.increment().unwrap());
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::AuthSent);
+        assert_eq!(ctx.signaling.initiator.session_key.as_ref(), Some(peer_session_ks.public_key()));
+        assert_eq!(actions.len(), 1); // Reply with auth msg
+
+        // The auth message is encrypted with the new session keypair
+        let obox = match actions.remove(0) {
+            HandleAction::Reply(bbox) =>
+                OpenBox::<Message>::decrypt(bbox, &peer_session_ks, &new_keypair, UnknownFields::Ignore).unwrap(),
+            other => panic!("Expected a reply, got {:?}", other),
+        };
+        assert_eq!(obox.message.get_type(), "auth");
+    }
 }
 
 mod auth {
@@ -871,6 +1679,52 @@ mod auth {
         assert_eq!(err, SignalingError::Protocol("Repeated cookie in auth message from initiator does not match our cookie".into()));
     }
 
+    /// A responder that reflects its own cookie in the your_cookie field is
+    /// rejected, and the handshake does not progress.
+    #[test]
+    fn initiator_reject_reflected_cookie() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+        let responder_cookie = Cookie::random();
+
+        let msg: Message = ResponderAuthBuilder::new(responder_cookie.clone())
+            .add_task(DummyTask::name_for(42), None)
+            .build()
+            .unwrap()
+            .into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build(responder_cookie, &responder.keypair, responder.session_key.as_ref().unwrap());
+        ctx.signaling.responders.insert(responder.address, responder);
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol("Repeated cookie in auth message from responder 0x03 does not match our cookie".into()));
+        assert_eq!(ctx.signaling.chosen_task(), None);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+    }
+
+    /// An initiator that reflects its own cookie in the your_cookie field is
+    /// rejected, and the handshake does not progress.
+    #[test]
+    fn responder_reject_reflected_cookie() {
+        let mut ctx = _auth_msg_prepare_responder();
+        let initiator_cookie = Cookie::random();
+
+        let msg: Message = InitiatorAuthBuilder::new(initiator_cookie.clone())
+            .set_task(DummyTask::name_for(42), None)
+            .build()
+            .unwrap()
+            .into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(3)
+            .build(initiator_cookie,
+                   &ctx.signaling.initiator.keypair,
+                   ctx.signaling.initiator.session_key.as_ref().unwrap());
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol("Repeated cookie in auth message from initiator does not match our cookie".into()));
+        assert_eq!(ctx.signaling.chosen_task(), None);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::AuthSent);
+    }
+
     /// An initiator SHALL validate that the tasks field contains an array with at least one element.
     #[test]
     fn initiator_task_field() {
@@ -1089,45 +1943,150 @@ mod auth {
         assert_eq!(ctx.signaling.get_peer().as_ref().unwrap().identity(), ctx.signaling.responder.as_ref().unwrap().identity());
 
         // Number of reply messages
-        assert_eq!(actions.len(), 4); // auth + drop-responder(5) + drop-responder(7) + HandshakeDone
+        // auth + drop-responder and event for each of the two other responders + HandshakeDone
+        assert_eq!(actions.len(), 6);
+        assert!(actions.contains(&HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(4)))));
+        assert!(actions.contains(&HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(7)))));
 
         // State transitions
         assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
         assert_eq!(ctx.signaling.responder.unwrap().handshake_state(), ResponderHandshakeState::AuthSent);
     }
 
+    /// With multiple registered tasks, the initiator chooses the first task
+    /// from its own list that the responder supports.
     #[test]
-    fn responder_choose_task() {
-        let mut ctx = _auth_msg_prepare_responder();
+    fn initiator_chosen_task() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+        ctx.signaling.common_mut().tasks.as_mut().unwrap()
+            .add_task(Box::new(DummyTask::new(7))).unwrap();
+
+        let msg: Message = ResponderAuthBuilder::new(responder.cookie_pair.ours.clone())
+            .add_task(DummyTask::name_for(1), None)
+            .add_task(DummyTask::name_for(7), None)
+            .build().unwrap()
+            .into_message();
+
+        assert_eq!(ctx.signaling.chosen_task(), None);
+        _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        assert_eq!(ctx.signaling.chosen_task(), Some(&*DummyTask::name_for(7)));
+    }
+
+    /// The data of the chosen task is passed on to the task.
+    #[test]
+    fn initiator_task_data_passed_to_task() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
 
+        let mut task_data = HashMap::new();
+        task_data.insert("nested".to_string(), Value::Map(vec![
+            (Value::from("foo"), Value::from(42)),
+        ]));
         let msg: Message = Auth {
-            your_cookie: ctx.signaling.initiator.cookie_pair.ours.clone(),
-            task: Some(DummyTask::name_for(42)),
-            tasks: None,
+            your_cookie: responder.cookie_pair.ours.clone(),
+            task: None,
+            tasks: Some(vec!["a".into(), DummyTask::name_for(42)]),
             data: {
                 let mut m = HashMap::new();
-                m.insert(DummyTask::name_for(42), None);
+                m.insert("a".into(), None);
+                m.insert(DummyTask::name_for(42), Some(task_data.clone()));
                 m
             },
         }.into_message();
 
-        // No task set so far
-        assert!(ctx.signaling.common().task.is_none());
-
-        // List of valid tasks contains 2 entries
-        assert_eq!(ctx.signaling.common().tasks.as_ref().unwrap().len(), 2);
-
-        // Signaling state is peer handshake
-        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
 
-        let actions = _auth_msg_handle_responder(msg, &mut ctx).unwrap();
+        let task = ctx.signaling.common().task.clone().unwrap();
+        let task = task.lock().unwrap();
+        let dummy = (&**task as &::tasks::Task).downcast_ref::<DummyTask>().unwrap();
+        assert!(dummy.initialized);
+        assert_eq!(dummy.init_data, Some(task_data));
+    }
 
-        // Task was set!
-        assert!(ctx.signaling.common().task.is_some());
-        assert_eq!(ctx.signaling.common().task.as_ref().unwrap().lock().unwrap().name(), DummyTask::name_for(42));
+    /// The initiator replies with an auth message encrypted with the session
+    /// keys, echoing the responder's cookie and containing the chosen task.
+    #[test]
+    fn initiator_send_auth() {
+        let (mut ctx, mut responder) = _auth_msg_prepare_initiator();
+        let peer_session_ks = KeyPair::new();
+        responder.session_key = Some(peer_session_ks.public_key().clone());
+        let our_session_pk = responder.keypair.public_key().clone();
+        let our_cookie = responder.cookie_pair.ours.clone();
+        let responder_cookie = Cookie::random();
 
-        // Tasks list was removed
-        assert!(ctx.signaling.common().tasks.is_none());
+        let msg: Message = Auth {
+            your_cookie: our_cookie.clone(),
+            task: None,
+            tasks: Some(vec![DummyTask::name_for(42)]),
+            data: {
+                let mut m = HashMap::new();
+                m.insert(DummyTask::name_for(42), None);
+                m
+            },
+        }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build(responder_cookie.clone(), &peer_session_ks, &our_session_pk);
+        ctx.signaling.responders.insert(responder.address, responder);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+
+        // Decrypt the auth message (the other replies are drop-responder
+        // messages to the server)
+        let bbox = actions.into_iter()
+            .filter_map(|action| match action {
+                HandleAction::Reply(bbox) => Some(bbox),
+                _ => None,
+            })
+            .find(|bbox| bbox.nonce.destination() == Address(3))
+            .expect("No auth message found");
+        let auth = OpenBox::<Message>::decrypt(
+            bbox, &peer_session_ks, &our_session_pk, UnknownFields::Ignore
+        ).unwrap();
+        assert_eq!(auth.nonce.cookie(), &our_cookie);
+        match auth.message {
+            Message::Auth(ref msg) => {
+                assert_eq!(msg.your_cookie, responder_cookie);
+                assert_eq!(msg.task, Some(DummyTask::name_for(42)));
+                assert_eq!(msg.tasks, None);
+                assert_eq!(msg.data.len(), 1);
+                assert_eq!(msg.data.get(&DummyTask::name_for(42)), Some(&None));
+            },
+            ref other => panic!("Expected Auth, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn responder_choose_task() {
+        let mut ctx = _auth_msg_prepare_responder();
+
+        let msg: Message = Auth {
+            your_cookie: ctx.signaling.initiator.cookie_pair.ours.clone(),
+            task: Some(DummyTask::name_for(42)),
+            tasks: None,
+            data: {
+                let mut m = HashMap::new();
+                m.insert(DummyTask::name_for(42), None);
+                m
+            },
+        }.into_message();
+
+        // No task set so far
+        assert!(ctx.signaling.common().task.is_none());
+        assert_eq!(ctx.signaling.chosen_task(), None);
+
+        // List of valid tasks contains 2 entries
+        assert_eq!(ctx.signaling.common().tasks.as_ref().unwrap().len(), 2);
+
+        // Signaling state is peer handshake
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+
+        let actions = _auth_msg_handle_responder(msg, &mut ctx).unwrap();
+
+        // Task was set!
+        assert!(ctx.signaling.common().task.is_some());
+        assert_eq!(ctx.signaling.common().task.as_ref().unwrap().lock().unwrap().name(), DummyTask::name_for(42));
+        assert_eq!(ctx.signaling.chosen_task(), Some(&*DummyTask::name_for(42)));
+
+        // Tasks list was removed
+        assert!(ctx.signaling.common().tasks.is_none());
 
         // Peer was set
         assert!(ctx.signaling.get_peer().is_some());
@@ -1236,7 +2195,101 @@ mod new_initiator {
 
         // ...and continue by sending a 'token' or 'key' client-to-client message
         // described in the Client-to-Client Messages section.
-        assert_eq!(actions.len(), 1);
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1], HandleAction::Event(Event::NewPeer(ClientIdentity::Initiator)));
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
+    }
+
+    /// A responder with an auth token should send a `token` message followed
+    /// by a `key` message when receiving a `new-initiator` message.
+    #[test]
+    fn handle_as_responder_with_token() {
+        let initiator_ks = KeyPair::new();
+        let token_bytes = [0x42; 32];
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(initiator_ks.public_key().clone()),
+            Some(AuthToken::from_slice(&token_bytes).unwrap()),
+        );
+
+        // Handle message
+        let bbox = ctx.next_server_msg(Message::NewInitiator(NewInitiator));
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 3);
+        assert_eq!(actions[2], HandleAction::Event(Event::NewPeer(ClientIdentity::Initiator)));
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
+
+        // The first message is a token message, encrypted with the auth token
+        let token = match actions.remove(0) {
+            HandleAction::Reply(bbox) => {
+                assert_eq!(bbox.nonce.destination(), Address(0x01));
+                OpenBox::<Message>::decrypt_token(
+                    bbox, &AuthToken::from_slice(&token_bytes).unwrap(), UnknownFields::Ignore
+                ).unwrap()
+            },
+            other => panic!("Expected Reply, got {:?}", other),
+        };
+        match token.message {
+            Message::Token(ref msg) => assert_eq!(&msg.key, ctx.our_ks.public_key()),
+            ref other => panic!("Expected Token, got {:?}", other),
+        }
+
+        // The second message is a key message, encrypted with the permanent keys
+        let key = match actions.remove(0) {
+            HandleAction::Reply(bbox) => {
+                assert_eq!(bbox.nonce.destination(), Address(0x01));
+                OpenBox::<Message>::decrypt(
+                    bbox, &initiator_ks, ctx.our_ks.public_key(), UnknownFields::Ignore
+                ).unwrap()
+            },
+            other => panic!("Expected Reply, got {:?}", other),
+        };
+        match key.message {
+            Message::Key(ref msg) => assert_eq!(&msg.key, ctx.signaling.initiator.keypair.public_key()),
+            ref other => panic!("Expected Key, got {:?}", other),
+        }
+
+        // The token has been consumed
+        assert!(ctx.signaling.common().auth_provider.is_none());
+    }
+
+    /// If no initiator is connected when the server handshake is done, the
+    /// responder waits for a `new-initiator` message before sending the
+    /// `token` and `key` messages.
+    #[test]
+    fn initiator_connected_later() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, Some(AuthToken::new()),
+        );
+
+        // No initiator connected so far
+        let msg = ServerAuth {
+            your_cookie: ctx.our_cookie.clone(),
+            signed_keys: None,
+            responders: None,
+            initiator_connected: Some(false),
+        }.into_message();
+        let bbox = ctx.next_server_msg(msg);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::ServerHandshakeDone(false))]);
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::Done);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::New);
+
+        // The initiator connects
+        let bbox = ctx.next_server_msg(Message::NewInitiator(NewInitiator));
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+
+        // Token and key are sent, and the application is notified
+        assert_eq!(actions.len(), 3);
+        assert!(actions[..2].iter().all(|action| match *action {
+            HandleAction::Reply(_) => true,
+            _ => false,
+        }));
+        assert_eq!(actions[2], HandleAction::Event(Event::NewPeer(ClientIdentity::Initiator)));
         assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
     }
 }
@@ -1244,6 +2297,32 @@ mod new_initiator {
 mod new_responder {
     use super::*;
 
+    /// A new-responder message for the chosen responder is ignored.
+    #[test]
+    fn chosen_responder_untouched() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done
+        );
+        let responder = ResponderContext::new(Address(0x04), 0);
+        let cookie = responder.cookie_pair().ours.clone();
+        ctx.signaling.responder = Some(responder);
+
+        let msg = Message::NewResponder(NewResponder { id: Address(0x04) });
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1)
+            .build(ctx.server_cookie.clone(),
+                   &ctx.server_ks,
+                   ctx.our_ks.public_key());
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![]);
+
+        let responder = ctx.signaling.responder.as_ref().expect("Chosen responder was removed");
+        assert_eq!(responder.address, Address(0x04));
+        assert_eq!(responder.cookie_pair().ours, cookie);
+        assert!(ctx.signaling.responders.is_empty());
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
+    }
+
     /// When a trusted key is available, the client should not expect a token
     /// message.
     #[test]
@@ -1280,7 +2359,99 @@ mod new_responder {
 
         // Handle message
         let actions = ctx.signaling.handle_message(bbox).unwrap();
-        assert_eq!(actions.len(), 1); // Drop responder
+        assert_eq!(actions.len(), 2); // Drop responder + event
+        assert_eq!(actions[1], HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(7))));
+    }
+
+    /// A valid new-responder message notifies the application about the
+    /// new peer.
+    #[test]
+    fn new_peer_event() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(0x2a) }));
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(0x2a)))]);
+        assert!(ctx.signaling.responders.contains_key(&Address(0x2a)));
+    }
+
+    /// A new-responder message with the highest responder address is
+    /// accepted.
+    #[test]
+    fn max_address() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(0xff) }));
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(0xff)))]);
+        assert!(ctx.signaling.responders.contains_key(&Address(0xff)));
+    }
+
+    /// A new-responder message with the server address is rejected.
+    #[test]
+    fn server_address() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(0) }));
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::InvalidMessage(
+                "`id` field in new-responder message contains the server address (0x00)".into()
+            ))
+        );
+        assert!(ctx.signaling.responders.is_empty());
+    }
+
+    /// A new-responder message with the initiator address is rejected.
+    #[test]
+    fn initiator_address() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(1) }));
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::InvalidMessage(
+                "`id` field in new-responder message contains the initiator address (0x01)".into()
+            ))
+        );
+        assert!(ctx.signaling.responders.is_empty());
+    }
+
+    /// By default, a responder ignores new-responder messages.
+    #[test]
+    fn received_by_responder_lenient() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(4) }));
+        assert_eq!(ctx.signaling.handle_message(bbox), Ok(vec![]));
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+    }
+
+    /// In strict mode, a responder must never receive a new-responder message.
+    #[test]
+    fn received_by_responder_strict() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.strict_protocol = true;
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(4) }));
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::Protocol("Received 'new-responder' message as responder".into()))
+        );
     }
 
     /// Path cleaning should be done when too many responders connect.
@@ -1308,12 +2479,91 @@ mod new_responder {
         // The first 252 responders should be registered just fine
         for i in 0..252 { // Waiting for inclusive ranges (1.26)
             let actions = handle_message(csn.increment().unwrap(), i + 2);
-            assert!(actions.is_empty());
+            assert_eq!(actions, vec![HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(i + 2)))]);
         }
 
         // The 253rd responder should result in a drop-responder message
         let actions = handle_message(csn.increment().unwrap(), 255);
-        assert_eq!(actions.len(), 1);
+        assert_eq!(actions.len(), 3); // Drop responder + events
+        assert_eq!(actions[1], HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(2))));
+        assert_eq!(actions[2], HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(255))));
+    }
+
+    /// With a lower responder limit, the oldest inactive responder is
+    /// dropped as soon as the limit is exceeded.
+    #[test]
+    fn max_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.max_responders = 2;
+
+        // The first two responders are registered
+        for i in &[3, 4] {
+            let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(*i) }));
+            let actions = ctx.signaling.handle_message(bbox).unwrap();
+            assert_eq!(actions.len(), 1); // Event
+        }
+
+        // The third responder evicts the first one
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(5) }));
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 3); // Drop responder + events
+        assert_eq!(ctx.signaling.responders.len(), 2);
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+        assert!(ctx.signaling.responders.contains_key(&Address(5)));
+    }
+
+    /// Responders are evicted in the order they were registered, regardless
+    /// of their addresses.
+    #[test]
+    fn eviction_order() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.max_responders = 3;
+
+        // Register responders in a known order
+        for i in &[7, 3, 5] {
+            let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(*i) }));
+            assert_eq!(ctx.signaling.handle_message(bbox).unwrap().len(), 1); // Event
+        }
+
+        // Every further responder evicts the oldest remaining one
+        for &(new, evicted) in &[(2, 7), (4, 3), (6, 5), (8, 2)] {
+            let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(new) }));
+            let actions = ctx.signaling.handle_message(bbox).unwrap();
+            assert_eq!(actions.len(), 3); // Drop responder + events
+            assert_eq!(actions[1], HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(evicted))));
+            assert_eq!(ctx.signaling.responders.len(), 3);
+            assert!(!ctx.signaling.responders.contains_key(&Address(evicted)));
+            assert!(ctx.signaling.responders.contains_key(&Address(new)));
+        }
+    }
+
+    /// The responder limit also applies to the responders listed in the
+    /// server-auth message.
+    #[test]
+    fn max_responders_server_auth() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        ctx.signaling.max_responders = 2;
+
+        // Handle server-auth with three responders
+        let responders = vec![Address(5), Address(3), Address(7)];
+        let msg = ServerAuth::for_initiator(ctx.our_cookie.clone(), None, responders).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.len(), 3); // Drop responder + events
+        assert_eq!(ctx.signaling.responders.len(), 2);
+        assert!(!ctx.signaling.responders.contains_key(&Address(5)));
+        assert!(ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(7)));
     }
 
 }
@@ -1388,7 +2638,8 @@ mod disconnected {
     }
 
     /// A receiving client MUST notify the user application about the incoming
-    /// 'disconnected' message, along with the id field.
+    /// 'disconnected' message, along with the id field. Exactly one event is
+    /// emitted per message.
     #[test]
     fn disconnected_notify_user() {
         let mut ctx = TestContext::initiator(
@@ -1405,8 +2656,52 @@ mod disconnected {
 
         // Handle message
         let actions = ctx.signaling.handle_message(bbox).unwrap();
-        assert_eq!(actions.len(), 1);
-        assert_eq!(actions[0], HandleAction::Event(Event::Disconnected(7)));
+        assert_eq!(actions, vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(7)))]);
+    }
+
+    /// If a responder disconnects during the peer handshake, the initiator
+    /// drops the responder context.
+    #[test]
+    fn disconnected_initiator_removes_responder() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.responders.insert(Address(7), ResponderContext::new(Address(7), 0));
+        ctx.signaling.responders.insert(Address(8), ResponderContext::new(Address(8), 1));
+
+        // Encrypt message
+        let msg = Disconnected::new(Address(7)).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+
+        // Handle message
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(7)))]);
+        assert!(!ctx.signaling.responders.contains_key(&Address(7)));
+        assert!(ctx.signaling.responders.contains_key(&Address(8)));
+    }
+
+    /// If the initiator disconnects during the peer handshake, the responder
+    /// resets the initiator context.
+    #[test]
+    fn disconnected_responder_resets_initiator() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        ctx.signaling.initiator.session_key = Some(PublicKey::random());
+
+        // Encrypt message
+        let msg = Disconnected::new(Address(1)).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(3).build_from_server(&ctx);
+
+        // Handle message
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Initiator))]);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::New);
+        assert_eq!(ctx.signaling.initiator.session_key, None);
     }
 
     /// A disconnected message should be processed by the initiator, even in
@@ -1427,7 +2722,745 @@ mod disconnected {
 
         // Handle message
         let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(7)))]);
+    }
+}
+
+
+mod send_error {
+    use ::protocol::send_error::SendErrorId;
+
+    use super::*;
+
+    /// Let the initiator handle a key message from responder 3, which
+    /// results in a key message being sent back to that responder.
+    fn initiator_with_key_sent() -> (TestContext<InitiatorSignaling>, ByteBox) {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        // Register two responders
+        let peer_permanent_pk = PublicKey::random();
+        let mut responder = ResponderContext::new(Address(3), 0);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        responder.permanent_key = Some(peer_permanent_pk.clone());
+        ctx.signaling.responders.insert(Address(3), responder);
+        ctx.signaling.responders.insert(Address(4), ResponderContext::new(Address(4), 1));
+
+        // Handle key message
+        let msg = Key { key: PublicKey::random() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1).build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap();
         assert_eq!(actions.len(), 1);
-        assert_eq!(actions[0], HandleAction::Event(Event::Disconnected(7)));
+        match actions.pop() {
+            Some(HandleAction::Reply(sent)) => (ctx, sent),
+            other => panic!("Expected reply, got {:?}", other),
+        }
+    }
+
+    /// A send-error for a message sent to a responder in the handshake
+    /// drops that responder.
+    #[test]
+    fn drops_affected_responder() {
+        let (mut ctx, sent) = initiator_with_key_sent();
+
+        let id = SendErrorId::from_nonce(&sent.nonce);
+        assert_eq!(id.destination, Address(3));
+        let bbox = ctx.next_server_msg(SendError { id }.into_message());
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::PeerDisconnected(ClientIdentity::Responder(3)))]);
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+    }
+
+    /// A send-error for a message that was not sent by us is ignored.
+    #[test]
+    fn unknown_message_id() {
+        let (mut ctx, sent) = initiator_with_key_sent();
+
+        let mut id = SendErrorId::from_nonce(&sent.nonce);
+        id.csn = CombinedSequenceSnapshot::new(id.csn.overflow_number(), id.csn.sequence_number().wrapping_add(1));
+        let bbox = ctx.next_server_msg(SendError { id }.into_message());
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![]);
+        assert!(ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+    }
+}
+
+mod sequence_numbers {
+    use super::*;
+
+    /// The peer MUST check that the combined sequence number of the source
+    /// peer has been increased by 1 and has not reset to 0.
+    #[test]
+    fn sequence_number_incremented() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        for i in 2..5 {
+            let bbox = ctx.next_server_msg(NewResponder { id: Address(i) }.into_message());
+            let csn = bbox.nonce.csn().clone();
+            ctx.signaling.handle_message(bbox).unwrap();
+            assert_eq!(ctx.signaling.server().csn_pair().borrow().theirs, Some(csn));
+        }
+        assert_eq!(ctx.signaling.responders.len(), 3);
+    }
+}
+
+mod unknown_fields {
+    use super::*;
+
+    /// Build a new-responder message from the server containing an
+    /// additional unknown field.
+    fn new_responder_with_unknown_field(ctx: &mut TestContext<InitiatorSignaling>) -> ByteBox {
+        let msg = NewResponder { id: Address(3) }.into_message();
+        let mut pairs = match ::rmp_serde::from_slice(&msg.to_msgpack()).unwrap() {
+            Value::Map(pairs) => pairs,
+            other => panic!("Expected a map, got {:?}", other),
+        };
+        pairs.push((Value::from("future_field"), Value::from(true)));
+        let bytes = ::rmp_serde::to_vec_named(&Value::Map(pairs)).unwrap();
+
+        let csn = ctx.server_csn.increment().unwrap();
+        let nonce = Nonce::new(ctx.server_cookie.clone(), Address(0), Address(1), csn);
+        let encrypted = ctx.server_ks.encrypt(&bytes, unsafe { nonce.clone() }, ctx.our_ks.public_key());
+        ByteBox::new(encrypted, nonce)
+    }
+
+    /// By default, unknown fields are ignored.
+    #[test]
+    fn ignore() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let bbox = new_responder_with_unknown_field(&mut ctx);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::Event(Event::NewPeer(ClientIdentity::Responder(3)))]);
+        assert!(ctx.signaling.responders.contains_key(&Address(3)));
+    }
+
+    /// In strict mode, unknown fields fail the signaling.
+    #[test]
+    fn reject() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.common_mut().unknown_fields = UnknownFields::Reject;
+        let bbox = new_responder_with_unknown_field(&mut ctx);
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::InvalidMessage(
+                "Unknown field \"future_field\" in 'new-responder' message".into()
+            ))
+        );
+        assert!(ctx.signaling.responders.is_empty());
+    }
+}
+
+
+mod reset {
+    use super::*;
+
+    /// By default, all cookies are regenerated on reset.
+    #[test]
+    fn fresh_cookies() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        let server_cookie = ctx.signaling.server().cookie_pair.ours.clone();
+        let initiator_cookie = ctx.signaling.initiator.cookie_pair.ours.clone();
+
+        ctx.signaling.reset_with_options(ResetOptions::default()).unwrap();
+
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::ServerHandshake);
+        assert_eq!(ctx.signaling.server_handshake_state(), ServerHandshakeState::New);
+        assert_eq!(ctx.signaling.identity(), ClientIdentity::Unknown);
+        assert!(ctx.signaling.server().session_key.is_none());
+        assert!(ctx.signaling.server().cookie_pair.theirs.is_none());
+        assert_ne!(ctx.signaling.server().cookie_pair.ours, server_cookie);
+        assert_ne!(ctx.signaling.initiator.cookie_pair.ours, initiator_cookie);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::New);
+    }
+
+    /// Cookies and CSNs can be carried over to the new connection.
+    #[test]
+    fn reuse_cookies() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        let server_cookie = ctx.signaling.server().cookie_pair.ours.clone();
+        let initiator_cookie = ctx.signaling.initiator.cookie_pair.ours.clone();
+        let server_csn = CombinedSequenceSnapshot::from(&ctx.signaling.server().csn_pair().borrow().ours);
+        let initiator_csn = CombinedSequenceSnapshot::from(&ctx.signaling.initiator.csn_pair.borrow().ours);
+
+        ctx.signaling.reset_with_options(ResetOptions {
+            reuse_server_cookie: true,
+            reuse_peer_cookie: true,
+        }).unwrap();
+
+        assert_eq!(ctx.signaling.server_handshake_state(), ServerHandshakeState::New);
+        assert_eq!(ctx.signaling.server().cookie_pair.ours, server_cookie);
+        assert!(ctx.signaling.server().cookie_pair.theirs.is_none());
+        assert_eq!(ctx.signaling.server().csn_pair().borrow().ours, server_csn);
+        assert!(ctx.signaling.server().csn_pair().borrow().theirs.is_none());
+        assert_eq!(ctx.signaling.initiator.cookie_pair.ours, initiator_cookie);
+        assert_eq!(ctx.signaling.initiator.csn_pair.borrow().ours, initiator_csn);
+        assert!(ctx.signaling.initiator.csn_pair.borrow().theirs.is_none());
+    }
+
+    /// Reset a responder that has received a message with overflow number 1
+    /// from the initiator, then validate the next initiator CSN.
+    fn _reset_and_validate_csn(options: ResetOptions) -> Result<(), ValidationError> {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.initiator.csn_pair.borrow_mut().theirs = Some(CombinedSequenceSnapshot::new(1, 5));
+
+        ctx.signaling.reset_with_options(options).unwrap();
+
+        let nonce = Nonce::new(Cookie::random(), Address(1), Address(3), CombinedSequenceSnapshot::new(1, 6));
+        ctx.signaling.validate_nonce_csn(&nonce)
+    }
+
+    /// After a clean reconnect, the first message must have overflow number 0.
+    #[test]
+    fn clean_reconnect_requires_overflow_zero() {
+        assert_eq!(
+            _reset_and_validate_csn(ResetOptions::default()),
+            Err(ValidationError::Fail("First message from initiator must have set the overflow number to 0".into()))
+        );
+    }
+
+    /// Reusing our peer cookie does not carry over the CSN of the peer, so
+    /// its first message must still have overflow number 0.
+    #[test]
+    fn reused_peer_cookie_requires_overflow_zero() {
+        let options = ResetOptions { reuse_server_cookie: false, reuse_peer_cookie: true };
+        assert_eq!(
+            _reset_and_validate_csn(options),
+            Err(ValidationError::Fail("First message from initiator must have set the overflow number to 0".into()))
+        );
+    }
+
+    /// After a reset that reuses our peer cookie, the initiator reconnects
+    /// with a new cookie. Its key message must be accepted.
+    #[test]
+    fn reused_peer_cookie_accepts_new_initiator_cookie() {
+        let initiator_ks = KeyPair::new();
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(initiator_ks.public_key().clone()), None,
+        );
+        let old_cookie = Cookie::random();
+        ctx.signaling.initiator.cookie_pair.theirs = Some(old_cookie.clone());
+        ctx.signaling.initiator.csn_pair.borrow_mut().theirs = Some(CombinedSequenceSnapshot::new(0, 5));
+        let our_cookie = ctx.signaling.initiator.cookie_pair.ours.clone();
+
+        ctx.signaling.reset_with_options(ResetOptions {
+            reuse_server_cookie: false,
+            reuse_peer_cookie: true,
+        }).unwrap();
+
+        // Server handshake on the new connection
+        let mut server = MockServer::for_responder();
+        let hello = server.server_hello();
+        let mut frames = replies(ctx.signaling.handle_message(hello).unwrap());
+        assert_eq!(frames.len(), 2);
+        server.receive(frames.remove(0));
+        server.receive_client_auth(frames.remove(0));
+        let server_auth = server.server_auth_for_responder(Address(3), true);
+        let frames = replies(ctx.signaling.handle_message(server_auth).unwrap());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].nonce.cookie(), &our_cookie);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
+
+        // The initiator uses a new cookie and starts its CSN over
+        let new_cookie = Cookie::random();
+        let msg = Key { key: PublicKey::random() }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(3).build_with_csn(
+            new_cookie.clone(), &initiator_ks, ctx.our_ks.public_key(), CombinedSequenceSnapshot::new(0, 1),
+        );
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(replies(actions).len(), 1);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::AuthSent);
+        assert_eq!(ctx.signaling.initiator.cookie_pair.theirs, Some(new_cookie));
+    }
+
+    /// The initiator forgets all responders on reset.
+    #[test]
+    fn initiator_forgets_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+
+        ctx.signaling.reset_with_options(ResetOptions::default()).unwrap();
+
+        assert_eq!(ctx.signaling.peer_count(), 0);
+        assert_eq!(ctx.signaling.identity(), ClientIdentity::Unknown);
+        assert_eq!(ctx.signaling.server_handshake_state(), ServerHandshakeState::New);
+    }
+
+    /// Once the peer handshake has finished, the signaling cannot be reset.
+    #[test]
+    fn not_in_task_state() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done,
+        );
+        assert_eq!(
+            ctx.signaling.reset_with_options(ResetOptions::default()),
+            Err(SignalingError::Protocol("Cannot reset the signaling after the peer handshake has finished".into()))
+        );
+    }
+
+    /// The permanent key can be replaced before the server handshake, e.g.
+    /// after a reset.
+    #[test]
+    fn set_permanent_key_after_reset() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.reset_with_options(ResetOptions::default()).unwrap();
+
+        let keypair = KeyPair::new();
+        let public_key = keypair.public_key().clone();
+        ctx.signaling.set_permanent_key(keypair).unwrap();
+        assert_eq!(ctx.signaling.common().permanent_keypair.public_key(), &public_key);
+    }
+
+    /// Once the server handshake has started, the permanent key cannot be
+    /// replaced anymore.
+    #[test]
+    fn set_permanent_key_during_handshake() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Unknown,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        let public_key = ctx.signaling.common().permanent_keypair.public_key().clone();
+        assert_eq!(
+            ctx.signaling.set_permanent_key(KeyPair::new()),
+            Err(SignalingError::Protocol("Cannot replace the permanent key after the server handshake has started".into()))
+        );
+        assert_eq!(ctx.signaling.common().permanent_keypair.public_key(), &public_key);
+    }
+}
+
+mod timeout {
+    use super::*;
+
+    /// A stalled server handshake results in close code 3008.
+    #[test]
+    fn server_handshake() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let actions = ctx.signaling.on_timeout(HandshakePhase::Server);
+        assert_eq!(actions, vec![HandleAction::Disconnect(CloseCode::Timeout)]);
+        assert_eq!(CloseCode::Timeout.as_number(), 3008);
+        assert_eq!(CloseCode::from_number(3008), CloseCode::Timeout);
+    }
+
+    /// A stalled peer handshake results in close code 3008.
+    #[test]
+    fn peer_handshake() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        let actions = ctx.signaling.on_timeout(HandshakePhase::Peer);
+        assert_eq!(actions, vec![HandleAction::Disconnect(CloseCode::Timeout)]);
+    }
+
+    /// Timeouts for phases that are already finished are ignored.
+    #[test]
+    fn phase_already_finished() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        assert_eq!(ctx.signaling.on_timeout(HandshakePhase::Server), vec![]);
+
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done,
+        );
+        assert_eq!(ctx.signaling.on_timeout(HandshakePhase::Server), vec![]);
+        assert_eq!(ctx.signaling.on_timeout(HandshakePhase::Peer), vec![]);
+    }
+}
+
+mod task {
+    use super::*;
+
+    /// Create a responder context in task state, along with the session
+    /// keypair of the initiator.
+    fn responder_in_task_state() -> (TestContext<ResponderSignaling>, KeyPair) {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::Task, ServerHandshakeState::Done,
+            None, None,
+        );
+        let initiator_session_ks = KeyPair::new();
+        ctx.signaling.initiator.session_key = Some(initiator_session_ks.public_key().clone());
+        (ctx, initiator_session_ks)
+    }
+
+    /// A close message sent to the peer contains the close code as reason.
+    #[test]
+    fn send_close() {
+        let (ctx, initiator_session_ks) = responder_in_task_state();
+
+        let bbox = ctx.signaling.encode_close_message(CloseCode::WsGoingAway, None).unwrap();
+        assert_eq!(bbox.nonce.source(), Address(3));
+        assert_eq!(bbox.nonce.destination(), Address(1));
+
+        let obox = OpenBox::<Message>::decrypt(
+            bbox,
+            &initiator_session_ks,
+            ctx.signaling.initiator.keypair.public_key(),
+            UnknownFields::Ignore,
+        ).unwrap();
+        assert_eq!(obox.message, Message::Close(Close::new(1001)));
+    }
+
+    /// Messages encrypted for the peer can be decrypted with the session
+    /// keys and use incrementing CSNs.
+    #[test]
+    fn encrypt_for_peer() {
+        let (ctx, initiator_session_ks) = responder_in_task_state();
+
+        let mut csns = vec![];
+        for code in &[3001, 3002] {
+            let msg = Message::Close(Close::new(*code));
+            let bbox = ctx.signaling.encrypt_for_peer(Identity::Initiator, msg.clone()).unwrap();
+            assert_eq!(bbox.nonce.source(), Address(3));
+            assert_eq!(bbox.nonce.destination(), Address(1));
+            csns.push(bbox.nonce.csn().combined_sequence_number());
+
+            let obox = OpenBox::<Message>::decrypt(
+                bbox,
+                &initiator_session_ks,
+                ctx.signaling.initiator.keypair.public_key(),
+                UnknownFields::Ignore,
+            ).unwrap();
+            assert_eq!(obox.message, msg);
+        }
+        assert_eq!(csns[1], csns[0] + 1);
+    }
+
+    /// A task message encrypted with the wrong key is reported as a
+    /// decryption failure rather than a decoding error.
+    #[test]
+    fn task_message_wrong_key() {
+        let (ctx, _initiator_session_ks) = responder_in_task_state();
+
+        let nonce = Nonce::new(Cookie::random(), Address(1), Address(3), CombinedSequenceSnapshot::random());
+        let bbox = OpenBox::<Value>::new(Value::from("hello"), nonce)
+            .encrypt(&KeyPair::new(), ctx.signaling.initiator.keypair.public_key());
+        assert_eq!(
+            ctx.signaling.decode_task_message(bbox),
+            Err(SignalingError::DecryptionFailed { from: Identity::Initiator })
+        );
+    }
+
+    /// Messages cannot be encrypted for the server or unknown peers.
+    #[test]
+    fn encrypt_for_peer_invalid() {
+        let (ctx, _) = responder_in_task_state();
+        let msg = Message::Close(Close::new(3001));
+        assert_eq!(
+            ctx.signaling.encrypt_for_peer(Identity::Server, msg.clone()),
+            Err(SignalingError::Crash("Cannot encrypt a peer message for the server".into()))
+        );
+        assert_eq!(
+            ctx.signaling.encrypt_for_peer(Identity::Responder(4), msg),
+            Err(SignalingError::Crash("Unknown peer: responder 0x04".into()))
+        );
+    }
+
+    /// A close message received from the peer is passed on as task message.
+    #[test]
+    fn receive_close() {
+        let (mut ctx, initiator_session_ks) = responder_in_task_state();
+
+        let msg = Value::Map(vec![
+            (Value::from("type"), Value::from("close")),
+            (Value::from("reason"), Value::from(3001)),
+        ]);
+        let nonce = Nonce::new(Cookie::random(), Address(1), Address(3), CombinedSequenceSnapshot::new(0, 42));
+        let bbox = OpenBox::<Value>::new(msg, nonce).encrypt(
+            &initiator_session_ks,
+            ctx.signaling.initiator.keypair.public_key(),
+        );
+
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![HandleAction::TaskMessage(TaskMessage::Close(CloseCode::ProtocolError))]);
+    }
+
+    /// Every outgoing message must be encrypted with a fresh nonce.
+    #[test]
+    fn no_nonce_reuse() {
+        let (ctx, _) = responder_in_task_state();
+
+        let value = || Value::Map(vec![(Value::from("type"), Value::from("dummy"))]);
+        let bbox1 = ctx.signaling.encode_task_message(value()).unwrap();
+        let bbox2 = ctx.signaling.encode_task_message(value()).unwrap();
+        let bbox3 = ctx.signaling.encode_close_message(CloseCode::WsGoingAway, None).unwrap();
+
+        let nonces: HashSet<[u8; 24]> = vec![bbox1, bbox2, bbox3].into_iter()
+            .map(|bbox| bbox.nonce.into_bytes())
+            .collect();
+        assert_eq!(nonces.len(), 3);
+    }
+}
+
+mod peer_message_routing {
+    use super::*;
+
+    fn close_from(source: u8, destination: u8, code: u16) -> OpenBox<Message> {
+        let nonce = Nonce::new(Cookie::random(), Address(source), Address(destination), CombinedSequenceSnapshot::random());
+        OpenBox::<Message>::new(Message::Close(Close::new(code)), nonce)
+    }
+
+    /// The initiator routes a token message from a new responder to the
+    /// token handler.
+    #[test]
+    fn initiator_valid_transition() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::random());
+        let obox = OpenBox::<Message>::new(Token { key: PublicKey::random() }.into_message(), nonce);
+
+        ctx.signaling.handle_peer_message(obox).unwrap();
+        assert_eq!(
+            ctx.signaling.responders.get(&Address(3)).unwrap().handshake_state(),
+            ResponderHandshakeState::TokenReceived
+        );
+    }
+
+    /// Undefined transitions on the initiator name the responder and the
+    /// responder handshake state.
+    #[test]
+    fn initiator_undefined_transition() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+
+        assert_eq!(
+            ctx.signaling.handle_peer_message(close_from(3, 1, 3001)),
+            Err(SignalingError::InvalidStateTransition(
+                "Got 'close' message from responder 0x03 in New state".into()
+            ))
+        );
+    }
+
+    /// The responder routes a close message during the peer handshake to
+    /// the close handler.
+    #[test]
+    fn responder_valid_transition() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::AuthSent);
+
+        assert_eq!(
+            ctx.signaling.handle_peer_message(close_from(1, 3, CloseCode::NoSharedTask.as_number())),
+            Err(SignalingError::NoSharedTask)
+        );
+    }
+
+    /// Undefined transitions on the responder name the initiator and the
+    /// initiator handshake state. The session keys are discarded.
+    #[test]
+    fn responder_undefined_transition() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        ctx.signaling.initiator.session_key = Some(PublicKey::random());
+
+        assert_eq!(
+            ctx.signaling.handle_peer_message(close_from(1, 3, 3001)),
+            Err(SignalingError::InvalidStateTransition(
+                "Got 'close' message from initiator in KeySent state".into()
+            ))
+        );
+        assert_eq!(ctx.signaling.initiator.session_key, None);
+    }
+
+    /// Server messages are not accepted from a peer, regardless of the
+    /// handshake state.
+    #[test]
+    fn peer_sends_server_message() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::random());
+        let obox = OpenBox::<Message>::new(Message::NewResponder(NewResponder { id: Address(4) }), nonce);
+        assert_eq!(
+            ctx.signaling.handle_peer_message(obox),
+            Err(SignalingError::InvalidMessage(
+                "Got 'new-responder' message from responder 0x03, but it is not a client-to-client message".into()
+            ))
+        );
+
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        let nonce = Nonce::new(Cookie::random(), Address(1), Address(3), CombinedSequenceSnapshot::random());
+        let obox = OpenBox::<Message>::new(ServerHello::random().into_message(), nonce);
+        assert_eq!(
+            ctx.signaling.handle_peer_message(obox),
+            Err(SignalingError::InvalidMessage(
+                "Got 'server-hello' message from initiator, but it is not a client-to-client message".into()
+            ))
+        );
+    }
+
+    /// Messages that are never sent by the server are rejected as invalid.
+    #[test]
+    fn server_sends_client_message() {
+        let messages = vec![
+            ClientHello::new(PublicKey::random()).into_message(),
+            Message::DropResponder(DropResponder::with_reason(Address(3), DropReason::ProtocolError)),
+            Message::Close(Close::new(3001)),
+        ];
+        for msg in messages {
+            let mut ctx = TestContext::initiator(
+                ClientIdentity::Initiator, None,
+                SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            );
+            let msg_type = msg.get_type();
+            let bbox = ctx.next_server_msg(msg);
+            assert_eq!(
+                ctx.signaling.handle_message(bbox),
+                Err(SignalingError::InvalidMessage(format!(
+                    "Got '{}' message from server, but it is not a server-to-client message", msg_type
+                )))
+            );
+        }
+    }
+
+    /// A server message in the wrong server handshake state names the
+    /// message type and the state.
+    #[test]
+    fn server_message_in_wrong_state() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let bbox = ctx.next_server_msg(Message::NewResponder(NewResponder { id: Address(3) }));
+        assert_eq!(
+            ctx.signaling.handle_message(bbox),
+            Err(SignalingError::InvalidStateTransition(
+                "Got 'new-responder' message from server in ClientInfoSent state".into()
+            ))
+        );
+    }
+}
+
+mod expected_message_types {
+    use super::*;
+
+    #[test]
+    fn server_handshake() {
+        let ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::New,
+        );
+        assert_eq!(ctx.signaling.expected_message_types(), vec!["server-hello"]);
+
+        let ctx = TestContext::responder(
+            ClientIdentity::Unknown,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        assert_eq!(ctx.signaling.expected_message_types(), vec!["server-auth"]);
+    }
+
+    /// Once the server handshake is done, the initiator expects server
+    /// messages as well as the messages of all responder handshakes.
+    #[test]
+    fn initiator_done() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        assert_eq!(ctx.signaling.expected_message_types(), vec!["new-responder", "send-error", "disconnected"]);
+
+        ctx.signaling.process_new_responder(Address(3)).unwrap();
+        assert_eq!(
+            ctx.signaling.expected_message_types(),
+            vec!["new-responder", "send-error", "disconnected", "token"]
+        );
+
+        ctx.signaling.process_new_responder(Address(4)).unwrap();
+        ctx.signaling.responders.get_mut(&Address(4)).unwrap()
+            .set_handshake_state(ResponderHandshakeState::TokenReceived);
+        assert_eq!(
+            ctx.signaling.expected_message_types(),
+            vec!["new-responder", "send-error", "disconnected", "token", "key"]
+        );
+    }
+
+    #[test]
+    fn responder_done() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+        assert_eq!(ctx.signaling.expected_message_types(), vec!["new-initiator", "send-error", "disconnected"]);
+
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::AuthSent);
+        assert_eq!(
+            ctx.signaling.expected_message_types(),
+            vec!["new-initiator", "send-error", "disconnected", "auth", "close"]
+        );
+    }
+
+    /// In the task state, peer messages are handled by the task.
+    #[test]
+    fn task() {
+        let ctx = TestContext::responder(
+            ClientIdentity::Responder(3),
+            SignalingState::Task, ServerHandshakeState::Done,
+            None, None,
+        );
+        assert_eq!(ctx.signaling.expected_message_types(), vec!["new-initiator", "send-error", "disconnected"]);
     }
 }
@@ -1,15 +1,19 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use self::clock::MockClock;
 use self::cookie::Cookie;
 use self::csn::CombinedSequenceSnapshot;
 use self::messages::*;
 
 use super::*;
+use super::mock_server::{MockServer, replies};
 
 /// A client MUST check that the destination address targets its assigned
 /// identity (or 0x00 during authentication).
 #[test]
 fn first_message_wrong_destination() {
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     let msg = ServerHello::random().into_message();
     let cs = CombinedSequenceSnapshot::random();
@@ -26,6 +30,34 @@ fn first_message_wrong_destination() {
     );
 }
 
+/// After an identity has been assigned, a message destined for another
+/// address is rejected, naming both addresses.
+#[test]
+fn wrong_destination_after_assignment() {
+    let ks = KeyPair::new();
+    let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+    s.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
+
+    // Identity is assigned with the first message
+    let make_nonce = |dest: u8| {
+        Nonce::new(Cookie::random(), Address(0), Address(dest), CombinedSequenceSnapshot::random())
+    };
+    assert_eq!(s.validate_nonce_destination(&make_nonce(0x05)), Ok(()));
+    assert_eq!(s.identity(), ClientIdentity::Responder(0x05));
+
+    // Messages for other addresses are rejected
+    assert_eq!(
+        s.validate_nonce_destination(&make_nonce(0x06)),
+        Err(ValidationError::Fail("Bad destination: 0x06 (expected 0x05, our identity is responder 0x05)".into()))
+    );
+    assert_eq!(
+        s.validate_nonce_destination(&make_nonce(0x01)),
+        Err(ValidationError::Fail("Bad destination: 0x01 (expected 0x05, our identity is responder 0x05)".into()))
+    );
+    assert_eq!(s.identity(), ClientIdentity::Responder(0x05));
+}
+
 /// An initiator SHALL ONLY process messages from the server (0x00). As
 /// soon as the initiator has been assigned an identity, it MAY ALSO accept
 /// messages from other responders (0x02..0xff). Other messages SHALL be
@@ -33,7 +65,7 @@ fn first_message_wrong_destination() {
 #[test]
 fn wrong_source_initiator() {
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     let make_msg = |src: u8, dest: u8| {
         let msg = ServerHello::random().into_message();
@@ -105,12 +137,190 @@ fn wrong_source_responder() {
     assert_eq!(actions.len(), 2);
 }
 
+/// A responder must reject being assigned an address outside of the
+/// responder range.
+#[test]
+fn responder_assigned_invalid_address() {
+    for &(dest, reason) in &[
+        (0x00, "cannot assign server address 0x00 to a responder"),
+        (0x01, "cannot assign initiator address 0x01 to a responder"),
+    ] {
+        let ks = KeyPair::new();
+        let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+        let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+        s.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
+
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(dest), CombinedSequenceSnapshot::random());
+        assert_eq!(s.validate_nonce_destination(&nonce), Err(ValidationError::Fail(reason.into())));
+        assert_eq!(s.identity(), ClientIdentity::Unknown);
+    }
+}
+
+/// A responder accepts being assigned an address in the responder range.
+#[test]
+fn responder_assigned_valid_address() {
+    for &dest in &[0x02, 0xff] {
+        let ks = KeyPair::new();
+        let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+        let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+        s.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
+
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(dest), CombinedSequenceSnapshot::random());
+        assert_eq!(s.validate_nonce_destination(&nonce), Ok(()));
+        assert_eq!(s.identity(), ClientIdentity::Responder(dest));
+    }
+}
+
+/// Too many consecutively dropped messages from the same source result in
+/// a disconnect.
+#[test]
+fn dropped_messages_threshold() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().max_dropped_messages = 3;
+
+    let make_msg = |src: u8| {
+        let msg = ServerHello::random().into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(src), Address(0x00), CombinedSequenceSnapshot::random());
+        OpenBox::<Message>::new(msg, nonce).encode()
+    };
+
+    // Messages from different sources are counted separately
+    for _ in 0..3 {
+        assert_eq!(s.handle_message(make_msg(0x01)), Ok(vec![]));
+        assert_eq!(s.handle_message(make_msg(0xff)), Ok(vec![]));
+    }
+    assert_eq!(
+        s.handle_message(make_msg(0x01)),
+        Ok(vec![HandleAction::Disconnect(CloseCode::ProtocolError)])
+    );
+}
+
+/// A message from a source with a valid nonce resets the dropped message
+/// counter of that source.
+#[test]
+fn dropped_messages_reset() {
+    let ks = KeyPair::new();
+    let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+    s.common_mut().max_dropped_messages = 3;
+
+    let make_msg = |dest: u8| {
+        let msg = ServerHello::random().into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(0x01), Address(dest), CombinedSequenceSnapshot::new(0, 1));
+        OpenBox::<Message>::new(msg, nonce).encode()
+    };
+
+    // Messages from the initiator are dropped as long as no identity is assigned
+    for _ in 0..3 {
+        assert_eq!(s.handle_message(make_msg(0x00)), Ok(vec![]));
+    }
+    assert_eq!(s.common().dropped_message_counts.get(&Address(0x01)), Some(&3));
+
+    // Valid nonce from the same source
+    s.common_mut().identity = ClientIdentity::Responder(0x02);
+    s.server_mut().set_handshake_state(ServerHandshakeState::Done);
+    let _ = s.handle_message(make_msg(0x02));
+    assert_eq!(s.common().dropped_message_counts.get(&Address(0x01)), None);
+}
+
+/// The reasons for dropped messages can be drained by the application.
+#[test]
+fn drain_dropped_messages() {
+    let ks = KeyPair::new();
+    let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+    assert_eq!(s.drain_dropped_messages(), vec![]);
+
+    // Messages from the initiator are dropped as long as no identity is assigned
+    let msg = ServerHello::random().into_message();
+    let nonce = Nonce::new(Cookie::random(), Address(0x01), Address(0x00), CombinedSequenceSnapshot::new(0, 1));
+    assert_eq!(s.handle_message(OpenBox::<Message>::new(msg, nonce).encode()), Ok(vec![]));
+
+    let dropped = s.drain_dropped_messages();
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].reason, "Bad source: 0x01 (our identity is unknown)");
+    assert_eq!(s.drain_dropped_messages(), vec![]);
+}
+
+/// Dropped messages are timestamped using the signaling clock.
+#[test]
+fn dropped_message_timestamps() {
+    let ks = KeyPair::new();
+    let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+    let clock = MockClock::new(1000);
+    s.common_mut().clock = Box::new(clock.clone());
+
+    let make_msg = || {
+        let msg = ServerHello::random().into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(0x01), Address(0x00), CombinedSequenceSnapshot::new(0, 1));
+        OpenBox::<Message>::new(msg, nonce).encode()
+    };
+    assert_eq!(s.handle_message(make_msg()), Ok(vec![]));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(s.handle_message(make_msg()), Ok(vec![]));
+
+    let dropped = s.drain_dropped_messages();
+    assert_eq!(dropped.len(), 2);
+    assert!(dropped.iter().all(|d| d.source == 0x01));
+    assert_eq!(dropped[0].timestamp, UNIX_EPOCH + Duration::from_secs(1000));
+    assert_eq!(dropped[1].timestamp, UNIX_EPOCH + Duration::from_secs(1005));
+    assert_eq!(s.drain_dropped_messages(), vec![]);
+}
+
+/// Source validation must never panic, regardless of the source address.
+#[test]
+fn source_boundaries_initiator() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().identity = ClientIdentity::Initiator;
+
+    let make_nonce = |src: u8| {
+        Nonce::new(Cookie::random(), Address(src), Address(0x01), CombinedSequenceSnapshot::random())
+    };
+
+    assert_eq!(s.validate_nonce_source(&make_nonce(0x00)), Ok(()));
+    assert_eq!(
+        s.validate_nonce_source(&make_nonce(0x01)),
+        Err(ValidationError::DropMsg("Bad source: 0x01 (our identity is initiator)".into()))
+    );
+    for src in 0x02..0x100 {
+        assert_eq!(s.validate_nonce_source(&make_nonce(src as u8)), Ok(()));
+    }
+}
+
+/// Source validation must never panic, regardless of the source address.
+#[test]
+fn source_boundaries_responder() {
+    let ks = KeyPair::new();
+    let initiator_pubkey = PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut s = ResponderSignaling::new(ks, initiator_pubkey, None, None, Tasks(vec![]), None);
+    s.common_mut().identity = ClientIdentity::Responder(0x02);
+
+    let make_nonce = |src: u8| {
+        Nonce::new(Cookie::random(), Address(src), Address(0x02), CombinedSequenceSnapshot::random())
+    };
+
+    assert_eq!(s.validate_nonce_source(&make_nonce(0x00)), Ok(()));
+    assert_eq!(s.validate_nonce_source(&make_nonce(0x01)), Ok(()));
+    for src in 0x02..0x100 {
+        let src = src as u8;
+        assert_eq!(
+            s.validate_nonce_source(&make_nonce(src)),
+            Err(ValidationError::DropMsg(
+                format!("Bad source: {} (our identity is responder 0x02)", Address(src))
+            ))
+        );
+    }
+}
+
 /// In case this is the first message received from the sender, the peer
 /// MUST check that the overflow number of the source peer is 0
 #[test]
 fn first_message_bad_overflow_number() {
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     let msg = ServerHello::random().into_message();
     let cs = CombinedSequenceSnapshot::new(1, 1234);
@@ -131,7 +341,7 @@ fn _test_sequence_number(first: CombinedSequenceSnapshot,
                          second: CombinedSequenceSnapshot)
                          -> SignalingResult<Vec<HandleAction>> {
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     // Process ServerHello
     let msg = ServerHello::random().into_message();
@@ -184,13 +394,80 @@ fn sequence_number_reset() {
     assert_eq!(err, SignalingError::InvalidNonce("The server CSN is lower than last time".into()));
 }
 
+/// The server CSN is accepted as long as it is incremented by 1 with
+/// every message, during and after the server handshake.
+#[test]
+fn server_csn_progression_and_replay() {
+    let ks = KeyPair::new();
+    let pk = ks.public_key().clone();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    let mut server = MockServer::for_initiator(pk);
+
+    let mut frames = replies(s.handle_message(server.server_hello()).unwrap());
+    server.receive_client_auth(frames.remove(0));
+    s.handle_message(server.server_auth_for_initiator(vec![])).unwrap();
+    s.handle_message(server.new_responder(Address(2))).unwrap();
+    let unknown_nonce = Nonce::new(Cookie::random(), Address(1), Address(2), CombinedSequenceSnapshot::random());
+    assert_eq!(s.handle_message(server.send_error(&unknown_nonce)), Ok(vec![]));
+
+    // Replaying the last message is rejected
+    let frame = server.new_responder(Address(3)).into_bytes();
+    s.handle_frame(&frame).unwrap();
+    assert_eq!(
+        s.handle_frame(&frame),
+        Err(SignalingError::InvalidNonce("The server CSN hasn't been incremented".into()))
+    );
+    assert_eq!(s.responders.len(), 2);
+}
+
+fn _validate_server_csns(csn_mode: CsnMode, sequence_numbers: &[u32]) -> Vec<Result<(), ValidationError>> {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().csn_mode = csn_mode;
+
+    let cookie = Cookie::random();
+    sequence_numbers.iter()
+        .map(|&sequence| {
+            let cs = CombinedSequenceSnapshot::new(0, sequence);
+            let nonce = Nonce::new(cookie.clone(), Address(0), Address(0), cs);
+            s.validate_nonce(&nonce)
+        })
+        .collect()
+}
+
+/// By default, a reordered CSN is rejected.
+#[test]
+fn sequence_number_reordered_strict() {
+    let results = _validate_server_csns(CsnMode::default(), &[1234, 1236, 1235]);
+    assert_eq!(results, vec![
+        Ok(()),
+        Ok(()),
+        Err(ValidationError::Fail("The server CSN is lower than last time".into())),
+    ]);
+}
+
+/// With a CSN window, a reordered CSN is accepted, but replays are
+/// still rejected.
+#[test]
+fn sequence_number_reordered_window() {
+    let results = _validate_server_csns(CsnMode::Window(8), &[1234, 1236, 1235, 1235, 1236, 1220]);
+    assert_eq!(results, vec![
+        Ok(()),
+        Ok(()),
+        Ok(()),
+        Err(ValidationError::Fail("The server CSN has already been used".into())),
+        Err(ValidationError::Fail("The server CSN hasn't been incremented".into())),
+        Err(ValidationError::Fail("The server CSN is lower than last time".into())),
+    ]);
+}
+
 /// In case this is the first message received from the sender, the
 /// peer MUST check that the sender's cookie is different than its own
 /// cookie.
 #[test]
 fn cookie_differs_from_own() {
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     let msg = ServerHello::random().into_message();
     let cookie = s.server().cookie_pair.ours.clone();
@@ -212,7 +489,7 @@ fn cookie_differs_from_own() {
 fn cookie_did_not_change() {
     // Create new signaling instance
     let ks = KeyPair::new();
-    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
 
     // Prepare 'server-hello' message
     let msg = ServerHello::random().into_message();
@@ -235,6 +512,113 @@ fn cookie_did_not_change() {
     // Handle 'server-auth' message
     assert_eq!(
         s.handle_message(bbox),
-        Err(SignalingError::InvalidNonce("Cookie from server has changed".into())),
+        Err(SignalingError::PeerCookieChanged(Identity::Server)),
     );
 }
+
+/// A changed cookie of a peer results in a distinct error.
+#[test]
+fn peer_cookie_changed() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().identity = ClientIdentity::Initiator;
+    s.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+
+    // The first message from the responder stores its cookie
+    let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::new(0, 1));
+    assert_eq!(s.validate_nonce(&nonce), Ok(()));
+
+    // The second message uses a different cookie
+    let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::new(0, 2));
+    let bbox = ByteBox::new(vec![1, 2, 3], nonce);
+    assert_eq!(
+        s.handle_message(bbox),
+        Err(SignalingError::PeerCookieChanged(Identity::Responder(3))),
+    );
+}
+
+/// A server-auth message received before the server-hello message must
+/// fail the signaling with an explicit error.
+#[test]
+fn server_auth_before_server_hello() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+
+    let msg = ServerAuth::for_initiator(s.server().cookie_pair().ours.clone(), None, vec![]).into_message();
+    let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 123));
+    let bbox = OpenBox::<Message>::new(msg, nonce).encode();
+
+    assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+    assert_eq!(
+        s.handle_message(bbox),
+        Err(SignalingError::InvalidStateTransition(
+            "Got 'server-auth' message from server before 'server-hello' message".into()
+        ))
+    );
+    assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+}
+
+/// In the `New` state, an encrypted message from the server must not be
+/// decrypted. Instead, a descriptive protocol error is returned.
+#[test]
+fn encrypted_server_auth_before_server_hello() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+
+    let server_ks = KeyPair::new();
+    let msg = ServerAuth::for_initiator(s.server().cookie_pair().ours.clone(), None, vec![]).into_message();
+    let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 123));
+    let bbox = OpenBox::<Message>::new(msg, nonce).encrypt(&server_ks, s.common().permanent_keypair.public_key());
+
+    assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+    assert_eq!(
+        s.handle_message(bbox),
+        Err(SignalingError::Protocol("Expected unencrypted message from server in state New".into()))
+    );
+    assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+}
+
+/// Once the server session key is known, an unencrypted message from the
+/// server must be rejected with a descriptive protocol error.
+#[test]
+fn unencrypted_server_auth_after_server_hello() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    let server_cookie = Cookie::random();
+    s.server_mut().set_handshake_state(ServerHandshakeState::ClientInfoSent);
+    s.server_mut().cookie_pair.theirs = Some(server_cookie.clone());
+    s.server_mut().session_key = Some(KeyPair::new().public_key().clone());
+
+    let msg = ServerAuth::for_initiator(s.server().cookie_pair().ours.clone(), None, vec![]).into_message();
+    let nonce = Nonce::new(server_cookie, Address(0), Address(1), CombinedSequenceSnapshot::new(0, 123));
+    let bbox = OpenBox::<Message>::new(msg, nonce).encode();
+
+    assert_eq!(
+        s.handle_message(bbox),
+        Err(SignalingError::Protocol("Expected encrypted message from server in state ClientInfoSent".into()))
+    );
+    assert_eq!(s.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
+}
+
+/// A peer cookie that mirrors the server cookie is rejected.
+#[test]
+fn peer_cookie_identical_to_server_cookie() {
+    let ks = KeyPair::new();
+    let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None, MAX_RESPONDERS);
+    s.common_mut().identity = ClientIdentity::Initiator;
+    s.server_mut().set_handshake_state(ServerHandshakeState::Done);
+    let server_cookie = Cookie::random();
+    s.server_mut().cookie_pair_mut().theirs = Some(server_cookie.clone());
+    s.process_new_responder(Address(0x03)).unwrap();
+
+    let nonce = Nonce::new(server_cookie, Address(0x03), Address(0x01), CombinedSequenceSnapshot::new(0, 1));
+    assert_eq!(
+        s.validate_nonce_cookie(&nonce),
+        Err(ValidationError::Fail("Cookie from responder 0x03 is identical to the server cookie".into()))
+    );
+    assert!(s.responders.get(&Address(0x03)).unwrap().cookie_pair().theirs.is_none());
+
+    // A different cookie is accepted
+    let nonce = Nonce::new(Cookie::random(), Address(0x03), Address(0x01), CombinedSequenceSnapshot::new(0, 1));
+    assert_eq!(s.validate_nonce_cookie(&nonce), Ok(()));
+}
@@ -1,13 +1,15 @@
 use std::convert::From;
 use std::fmt;
 use std::result::Result as StdResult;
+use std::time::SystemTime;
 
 use serde::ser::{Serialize, Serializer};
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
 
-use ::Event;
+use ::{Event, CloseCode};
 use ::boxes::ByteBox;
-use ::errors::SaltyError;
+use ::errors::{SaltyError, SignalingError, SignalingResult};
+use ::protocol::state::{SignalingState, ServerHandshakeState};
 use ::tasks::TaskMessage;
 
 
@@ -84,7 +86,7 @@ impl fmt::Display for Identity {
 /// is not allowed. Additionally, the `Unknown` value can be used for identities
 /// that aren't initialized yet.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub(crate) enum ClientIdentity {
+pub enum ClientIdentity {
     /// An unknown identity is initialized to `0x00`.
     Unknown,
     /// The initiator has the identity `0x01`.
@@ -104,11 +106,66 @@ impl fmt::Display for ClientIdentity {
 }
 
 
+/// A read-only summary of the current signaling session.
+///
+/// This is intended for logging and metrics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    /// The role of this client.
+    pub role: Role,
+    /// The identity assigned by the server.
+    pub identity: ClientIdentity,
+    /// The overall signaling state.
+    pub signaling_state: SignalingState,
+    /// The state of the server handshake.
+    pub server_handshake_state: ServerHandshakeState,
+    /// The number of responders known to the initiator. This is always 0
+    /// for responders.
+    pub responder_count: usize,
+}
+
+
+/// Diagnostics about a message that was dropped during nonce validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DroppedMessage {
+    /// The source address of the message (`0x00` for the server, `0x01` for
+    /// the initiator, `0x02-0xff` for a responder).
+    pub source: u8,
+    /// The reason why the message was dropped.
+    pub reason: String,
+    /// When the message was dropped.
+    pub timestamp: SystemTime,
+}
+
+/// Options for [`SaltyClient::reset`](struct.SaltyClient.html#method.reset).
+///
+/// The SaltyRTC protocol requires a fresh cookie for every new connection,
+/// which is the default. Reusing a cookie also keeps our CSN towards that
+/// party, which is only useful if the other side keeps track of CSNs across
+/// reconnects. Otherwise the other side will treat the reused cookie like any
+/// other new cookie.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ResetOptions {
+    /// Keep our cookie and CSN towards the server.
+    pub reuse_server_cookie: bool,
+    /// Keep our cookie and CSN towards the peer.
+    ///
+    /// The cookie and CSN of the peer are always discarded: A peer picks a
+    /// new cookie on every connection, so its next message is treated as a
+    /// first message and must have overflow number 0.
+    ///
+    /// The initiator forgets all responders on reset, so this only has an
+    /// effect for responders.
+    pub reuse_peer_cookie: bool,
+}
+
 /// An address.
 ///
 /// This is an unsigned byte like the [`Identity`](enum.Identity.html),
 /// but without any semantic information attached.
-#[derive(PartialEq, Eq, Copy, Clone, Hash)]
+///
+/// Addresses are ordered by their numeric value.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Hash)]
 pub(crate) struct Address(pub(crate) u8);
 
 impl Address {
@@ -131,6 +188,20 @@ impl Address {
     pub(crate) fn is_responder(&self) -> bool {
         self.0 >= 0x02
     }
+
+    /// Convert a [`ClientIdentity`](enum.ClientIdentity.html) into the
+    /// corresponding address.
+    ///
+    /// Unlike the `From` implementation, this returns an error instead of
+    /// panicking if a `Responder` with an out-of-range value is encountered.
+    pub(crate) fn try_from_client_identity(val: ClientIdentity) -> SignalingResult<Self> {
+        match val {
+            ClientIdentity::Responder(address) if address <= 0x01 => Err(SignalingError::Crash(
+                format!("Invalid responder address: {:#04x}", address)
+            )),
+            other => Ok(other.into()),
+        }
+    }
 }
 
 impl fmt::Display for Address {
@@ -151,6 +222,8 @@ impl From<ClientIdentity> for Address {
     /// corresponding address.
     ///
     /// Panics if a `Responder` with an out-of-range value is encountered.
+    /// Use [`Address::try_from_client_identity`](struct.Address.html#method.try_from_client_identity)
+    /// if the value has not been validated.
     fn from(val: ClientIdentity) -> Self {
         Address(match val {
             ClientIdentity::Unknown => 0x00,
@@ -231,6 +304,8 @@ pub(crate) enum HandleAction {
     Event(Event),
     /// A task message was received and decoded.
     TaskMessage(TaskMessage),
+    /// Close the connection to the server with the specified close code.
+    Disconnect(CloseCode),
 }
 
 
@@ -257,6 +332,19 @@ mod tests {
         let _: Address = responder_invalid.into();
     }
 
+    /// Converting an invalid `Responder` with the fallible constructor
+    /// returns an error.
+    #[test]
+    fn client_identity_try_into_address() {
+        assert_eq!(Address::try_from_client_identity(ClientIdentity::Unknown), Ok(Address(0x00)));
+        assert_eq!(Address::try_from_client_identity(ClientIdentity::Initiator), Ok(Address(0x01)));
+        assert_eq!(Address::try_from_client_identity(ClientIdentity::Responder(0x13)), Ok(Address(0x13)));
+        assert_eq!(
+            Address::try_from_client_identity(ClientIdentity::Responder(0x01)),
+            Err(SignalingError::Crash("Invalid responder address: 0x01".into()))
+        );
+    }
+
     #[test]
     fn address_display() {
         assert_eq!(format!("{}", Address(0)), "0x00");
@@ -264,6 +352,24 @@ mod tests {
         assert_eq!(format!("{}", Address(255)), "0xff");
     }
 
+    /// The highest address is a valid responder address.
+    #[test]
+    fn max_responder_address() {
+        assert!(Address(0xff).is_responder());
+        assert_eq!(Identity::from(Address(0xff)), Identity::Responder(0xff));
+        assert_eq!(Address::from(Identity::Responder(0xff)), Address(0xff));
+        assert_eq!(Address::from(ClientIdentity::Responder(0xff)), Address(0xff));
+    }
+
+    #[test]
+    fn address_ordering() {
+        assert!(Address(0x00) < Address(0x01));
+        assert!(Address(0x02) < Address(0xff));
+        let mut addresses = vec![Address(0xff), Address(0x03), Address(0x02), Address(0x10)];
+        addresses.sort();
+        assert_eq!(addresses, vec![Address(0x02), Address(0x03), Address(0x10), Address(0xff)]);
+    }
+
     #[test]
     fn client_identity_display() {
         let unknown = ClientIdentity::Unknown;
@@ -14,10 +14,11 @@ use ::CloseCode;
 use tasks::{Task, TaskMessage};
 
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub(crate) struct DummyTask {
     pub id: u8,
     pub initialized: bool,
+    pub init_data: Option<HashMap<String, Value>>,
 }
 
 impl DummyTask {
@@ -25,6 +26,7 @@ impl DummyTask {
         DummyTask {
             id,
             initialized: false,
+            init_data: None,
         }
     }
 
@@ -34,8 +36,9 @@ impl DummyTask {
 }
 
 impl Task for DummyTask {
-    fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+    fn init(&mut self, data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
         self.initialized = true;
+        self.init_data = data.clone();
         Ok(())
     }
 